@@ -1,9 +1,14 @@
 use std::process::{Command, Stdio};
-use std::process;
+use std::path::Path;
+use std::{fs, process};
 
 use log::error;
 
-use crate::components::config::Config;
+use crate::components::config::{AccessControl, Config};
+
+const APPARMOR_ENABLED_PATH: &str = "/sys/module/apparmor/parameters/enabled";
+const APPARMOR_PROFILES_PATH: &str = "/sys/kernel/security/apparmor/profiles";
+const SELINUX_ENFORCE_PATH: &str = "/sys/fs/selinux/enforce";
 
 pub fn run_check(config_path: Option<&str>) {
 
@@ -30,6 +35,7 @@ pub fn run_check(config_path: Option<&str>) {
 
     check_git_binary();
     check_nerdctl_binary(&config);
+    check_security_policy(&config);
     
     println!();
 
@@ -76,3 +82,96 @@ fn check_nerdctl_binary(config: &Config) {
     }
     println!("OK, nerdctl binary launched");
 }
+
+fn check_security_policy(config: &Config) {
+
+    let security = &config.container.security;
+
+    match security.access_control {
+        AccessControl::Apparmor => {
+
+            let is_enabled = fs::read_to_string(APPARMOR_ENABLED_PATH)
+                .map(|content| content.trim() == "Y")
+                .unwrap_or(false);
+            if !is_enabled {
+                error!("FAIL, AppArmor is not enabled on this host (set 'access_control' to 'selinux' or 'none')");
+                process::exit(1);
+            }
+
+            // The docker-default profile is generated by nerdctl when missing
+            if security.apparmor_profile != "docker-default" {
+
+                let profiles = fs::read_to_string(APPARMOR_PROFILES_PATH).unwrap_or_default();
+                let is_loaded = profiles.lines().any(|line| line.split(' ').next() == Some(security.apparmor_profile.as_str()));
+                if !is_loaded {
+                    error!("FAIL, AppArmor profile '{}' is not loaded", security.apparmor_profile);
+                    process::exit(1);
+                }
+            }
+            println!("OK, AppArmor profile '{}' available", security.apparmor_profile);
+        },
+        AccessControl::Selinux => {
+
+            if !Path::new(SELINUX_ENFORCE_PATH).exists() {
+                error!("FAIL, SELinux is not enabled on this host (set 'access_control' to 'apparmor' or 'none')");
+                process::exit(1);
+            }
+            println!("OK, SELinux available");
+        },
+        AccessControl::None => {
+            println!("WARN, no mandatory access control configured for containers");
+        }
+    }
+
+    if let Some(seccomp_profile) = &security.seccomp_profile {
+
+        let profile_content = fs::read_to_string(seccomp_profile).unwrap_or_else(|err| {
+            error!("FAIL, could not read seccomp profile {} ({})", seccomp_profile, err);
+            process::exit(1);
+        });
+        if let Err(err) = serde_json::from_str::<serde_json::Value>(&profile_content) {
+            error!("FAIL, seccomp profile {} is not a valid JSON document ({})", seccomp_profile, err);
+            process::exit(1);
+        }
+        println!("OK, seccomp profile available");
+    }
+
+    if let Some(runtime) = &security.runtime {
+
+        // Containerd shim names (io.containerd.runsc.v1) map to a shim binary
+        let runtime_binary = match runtime.strip_prefix("io.containerd.") {
+            Some(shim_name) => format!("containerd-shim-{}", shim_name.replace('.', "-")),
+            None => runtime.to_string()
+        };
+
+        let runtime_result = Command::new(&runtime_binary)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        let runtime_status = runtime_result.unwrap_or_else(|err| {
+            error!("FAIL, could not launch the '{}' runtime binary ({})", runtime_binary, err);
+            process::exit(1);
+        });
+        if !runtime_status.success() {
+            error!("FAIL, could not launch the '{}' runtime binary (status {})", runtime_binary, runtime_status);
+            process::exit(1);
+        }
+        println!("OK, runtime '{}' available", runtime);
+    }
+
+    if let Some(default_user) = &security.default_user {
+
+        let user_name = default_user.split(':').next().unwrap_or_default();
+        if user_name == "root" || user_name == "0" {
+            error!("FAIL, default container user should not be root");
+            process::exit(1);
+        }
+    }
+
+    if let Some(masked_path) = security.masked_paths.iter().find(|masked_path| !masked_path.path.starts_with('/')) {
+        error!("FAIL, masked path '{}' should be absolute", masked_path.path);
+        process::exit(1);
+    }
+}
@@ -1,34 +1,199 @@
 use std::process;
 use std::time::Duration;
 use std::rc::Rc;
+use std::io;
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
 
 use anyhow::{bail, Context, Error, Result};
 use env_logger::Env;
 use log::{info, error, warn, debug};
+use serde::{Deserialize, Serialize};
 use url::Url;
-use serde::Deserialize;
 
-use crate::models::CodeIssue;
+use crate::models::{CodeFunction, CodeIssue, FailureReason, QualityGate, Scan};
+use crate::parsers::{detect_format, parse_report, ReportFormat};
 use crate::components::{
     scheduler::{authenticate_runner, Scheduler, try_scheduler_ws_connection, Ws},
     workspace::Workspace,
-    config::{Config, TOKEN_ENV},
-    container::run_container
+    artifacts::{collect_artifacts, Artifact},
+    config::{Config, ProtocolVersion, TOKEN_ENV},
+    container::run_container,
+    image::{collect_images, prewarm_images},
+    proxy::EgressProxy,
+    results::{limit_results, read_issue_file, read_metrics, read_sboms, report_problems, sbom_metrics, ResultReader, ISSUES_FILES, METRICS_FILES},
+    index::{diff_metric, IssueIndex},
+    gates::{evaluate_gates, parse_repository_gates, report_violations, select_gates},
+    suppressions::{Suppressions, REPOSITORY_CONFIG_FILE},
+    tracker::{CancelSignal, ContainerTracker}
 };
+use crate::utils::current_date;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Default)]
 struct ScanRequest {
     _version: String,
+    request_id: Option<String>,     // Only given by v2 messages, echoed in replies
     repositories: Vec<String>,
-    functions: Vec<String>
+    functions: Vec<String>,
+    git_ref: Option<String>,        // Branch, tag or commit, the default branch otherwise
+    options: ScanOptions
+}
+
+#[derive(Deserialize, PartialEq, Debug, Default, Clone, Copy)]
+struct ScanOptions {
+    timeout: Option<u64>,   // Seconds, overrides the stage timeout
+    #[serde(default)]
+    priority: i64           // Queued requests with a higher priority are scanned first
 }
 
+/// Messages sent by the control plane once the runner is authenticated
+#[derive(PartialEq, Debug)]
+enum RunnerMessage {
+    Scan(ScanRequest),
+    Cancel(CancelRequest)
+}
+
+/// Cancellation of the scan running on a repository, v2 cancellations may
+/// instead target a whole scan request, whether it is queued or running
+#[derive(PartialEq, Debug)]
+struct CancelRequest {
+    request_id: Option<String>,
+    repository_id: Option<String>,
+    scan_request_id: Option<String>
+}
+
+/// JSON framed message of the v2 protocol
 #[derive(Deserialize)]
-struct IssueContainer {
-    issues: Vec<CodeIssue>
+#[serde(rename_all = "camelCase")]
+struct ProtocolMessage {
+    version: String,
+    #[serde(rename = "type")]
+    message_type: ProtocolMessageType,
+    request_id: String,
+    #[serde(default)]
+    repositories: Vec<String>,
+    #[serde(default)]
+    functions: Vec<String>,
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+    #[serde(default)]
+    options: ScanOptions,
+    scan_request_id: Option<String>     // Cancellations only
+}
+
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+enum ProtocolMessageType {
+    Scan,
+    Cancel
+}
+
+/// Reply sent to the control plane for v2 messages, correlated with the request ID
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RunnerReply {
+    version: &'static str,
+    #[serde(rename = "type")]
+    reply_type: RunnerReplyType,
+    request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum RunnerReplyType {
+    Accepted,
+    Rejected,
+    Started,
+    Completed,
+    Failed,
+    Cancelled
 }
 
+impl RunnerReply {
+
+    fn new(request_id: &str, reply_type: RunnerReplyType, error: Option<String>) -> RunnerReply {
+        RunnerReply {
+            version: "v2",
+            reply_type,
+            request_id: request_id.to_string(),
+            error
+        }
+    }
+
+}
+
+/// Scan requests decoded by the reader thread, replies are written back by the same thread
+struct ControlChannel {
+    requests: Receiver<ScanRequest>,
+    replies: Sender<RunnerReply>,
+    pending: Vec<ScanRequest>,
+    cancel_signal: CancelSignal
+}
+
+impl ControlChannel {
+
+    /// Wait for a scan request, the queued request with the highest priority is returned first
+    /// and queued requests cancelled in the meantime are dropped
+    fn next_request(&mut self) -> ScanRequest {
+
+        loop {
+            if self.pending.is_empty() {
+                let request = self.requests.recv().unwrap_or_else(|err| {
+                    error!("Scheduler message reader stopped, internal logic error ({})", err);
+                    process::exit(1);
+                });
+                self.pending.push(request);
+            }
+            self.pending.extend(self.requests.try_iter());
+            self.drop_cancelled();
+
+            if let Some(request) = select_request(&mut self.pending) {
+                if let Some(request_id) = &request.request_id {
+                    self.cancel_signal.start(request_id);
+                }
+                return request;
+            }
+        }
+    }
+
+    fn drop_cancelled(&mut self) {
+
+        let (cancelled, pending): (Vec<ScanRequest>, Vec<ScanRequest>) = self.pending.drain(..)
+            .partition(|request| request.request_id.as_deref().is_some_and(|request_id| self.cancel_signal.is_cancelled(request_id)));
+        self.pending = pending;
+
+        for request in cancelled {
+            info!("Dropped cancelled request for repository IDs {}", request.repositories.join(","));
+            self.reply(&request, RunnerReplyType::Cancelled, None);
+            if let Some(request_id) = &request.request_id {
+                self.cancel_signal.finish(request_id);
+            }
+        }
+    }
+
+    /// Replies are only sent for v2 requests, v1 messages do not carry a request ID
+    fn reply(&self, request: &ScanRequest, reply_type: RunnerReplyType, error: Option<String>) {
+
+        if let Some(request_id) = &request.request_id {
+            let _ = self.replies.send(RunnerReply::new(request_id, reply_type, error));
+        }
+    }
+
+}
+
+/// Remove the first request with the highest priority
+fn select_request(pending: &mut Vec<ScanRequest>) -> Option<ScanRequest> {
+
+    let highest_priority = pending.iter().map(|request| request.options.priority).max()?;
+    let position = pending.iter().position(|request| request.options.priority == highest_priority)?;
+
+    Some(pending.remove(position))
+}
+
+#[allow(clippy::manual_inspect)]
 pub fn launch_runner(config_path: Option<&str>, workspace_option: Option<&String>, ns_option: Option<&String>) -> Result<(), Error> {
 
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
@@ -84,75 +249,581 @@ pub fn launch_runner(config_path: Option<&str>, workspace_option: Option<&String
     info!("Attempting connection on control plane ({})", websocker_raw_url);
     let websocket_url = Url::parse(&websocker_raw_url)?;
 
-    let mut websocket = try_scheduler_ws_connection(shared_config.clone(), &websocket_url);       
+    let (mut websocket, mut tcp_stream) = try_scheduler_ws_connection(shared_config.clone(), &websocket_url);       
     info!("Connected to the scheduler, sending authentication request");
 
-    authenticate_runner(shared_config.clone(), &mut websocket);
+    let offered_protocol = shared_config.scheduler.protocol;
+    let mut negotiated_protocol = authenticate_runner(shared_config.clone(), &mut websocket, offered_protocol);
+
+    // Older schedulers only accept the bare token
+    if negotiated_protocol.is_none() && offered_protocol != ProtocolVersion::V1 {
+        warn!("Scheduler refused the {:?} authentication, retrying with the v1 bare token", offered_protocol);
+        (websocket, tcp_stream) = try_scheduler_ws_connection(shared_config.clone(), &websocket_url);
+        negotiated_protocol = authenticate_runner(shared_config.clone(), &mut websocket, ProtocolVersion::V1);
+    }
+    let protocol = negotiated_protocol.unwrap_or_else(|| {
+        error!("Authentication failed, check the runner token");
+        process::exit(1);
+    });
+
+    apply_image_retention(&shared_config, &shared_scheduler, &shared_workspace);
+
+    if shared_config.container.prewarm {
+        match shared_scheduler.get_functions(&[]) {
+            Ok(code_functions) => prewarm_images(&shared_config.container, &code_functions),
+            Err(err) => warn!("Could not retrieve functions for image pre-warming ({})", err)
+        }
+    }
+
+    let runner_id = match &shared_config.container.runner_id {
+        Some(runner_id) => runner_id.clone(),
+        None => shared_workspace.get_runner_id().context("Could not generate the runner identifier")?
+    };
+    info!("Labeling containers with runner identifier '{}'", runner_id);
+
+    let tracker = ContainerTracker::new(&shared_config.container, &runner_id);
+    match tracker.cleanup_orphans() {
+        Ok(0) => {},
+        Ok(removed_count) => warn!("Removed {} orphan containers from a previous runner process", removed_count),
+        Err(err) => warn!("Could not clean orphan containers ({})", err)
+    }
+    tracker.start_cleanup_timer(Duration::from_secs(shared_config.container.orphan_check_period));
+    let shared_tracker = Rc::new(tracker);
+
+    let shared_proxy: Option<Rc<EgressProxy>> = EgressProxy::start(&shared_config.container).map(Rc::new);
+
+    // The websocket is read in a dedicated thread so that cancellations are
+    // received while scans are running
+    let mut control_channel = spawn_message_reader(websocket, &tcp_stream, protocol, shared_tracker.cancel_signal().clone(), shared_config.scheduler.retry_period);
 
     loop {
-        process_message(&mut websocket, shared_config.clone(), shared_scheduler.clone(), shared_workspace.clone()).unwrap_or_else(|err| {
-            error!("Could not process message due to error: {} ({})", err, err.root_cause().to_string());
+        process_message(&mut control_channel, shared_config.clone(), shared_scheduler.clone(), shared_workspace.clone(), shared_tracker.clone(), shared_proxy.clone()).unwrap_or_else(|err| {
+            error!("Could not process message due to error: {} ({})", err, err.root_cause());
         });
     }
 }
 
 
-fn process_message(websocket: &mut Ws, shared_config: Rc<Config>, scheduler: Rc<Scheduler>, workspace: Rc<Workspace>) -> Result<(), Error> {
+fn spawn_message_reader(mut websocket: Ws, tcp_stream: &TcpStream, protocol: ProtocolVersion, cancel_signal: CancelSignal, retry_period: u64) -> ControlChannel {
+
+    let (sender, receiver) = mpsc::channel::<ScanRequest>();
+    let (reply_sender, reply_receiver) = mpsc::channel::<RunnerReply>();
 
-    let socket_read_result = websocket.read();
+    // Reads are interrupted periodically so that replies are not delayed until the next message,
+    // the timeout is set on the TCP socket so that it also applies below TLS
+    if protocol == ProtocolVersion::V2 {
+        tcp_stream.set_read_timeout(Some(Duration::from_millis(250))).unwrap_or_else(|err| {
+            warn!("Could not set read timeout on scheduler socket ({})", err);
+        });
+    }
 
-    if let Err(read_error) = socket_read_result {
+    let thread_replies = reply_sender.clone();
+    let channel_cancel_signal = cancel_signal.clone();
+    thread::spawn(move || {
+        loop {
 
-        error!("Failed to read messages from scheduler: {}", read_error);
+            for reply in reply_receiver.try_iter() {
+                send_reply(&mut websocket, &reply);
+            }
 
-        let duration = Duration::from_secs(shared_config.scheduler.retry_period);
-        thread::sleep(duration);
+            let raw_message = match websocket.read() {
+                Ok(raw_message) => raw_message,
+                Err(tungstenite::Error::Io(err)) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    continue;
+                },
+                Err(read_error) => {
 
-        return Ok(());
+                    error!("Failed to read messages from scheduler: {}", read_error);
+
+                    let duration = Duration::from_secs(retry_period);
+                    thread::sleep(duration);
+                    continue;
+                }
+            };
+
+            match decode_runner_message(raw_message, protocol) {
+                Ok(RunnerMessage::Scan(request)) => {
+                    if let Some(request_id) = &request.request_id {
+                        cancel_signal.queue(request_id);
+                        send_reply(&mut websocket, &RunnerReply::new(request_id, RunnerReplyType::Accepted, None));
+                    }
+                    if sender.send(request).is_err() {
+                        return;
+                    }
+                },
+                Ok(RunnerMessage::Cancel(cancel_request)) => {
+                    let is_cancelled = apply_cancellation(&cancel_request, &cancel_signal);
+
+                    if let Some(request_id) = cancel_request.request_id {
+                        let reply = match is_cancelled {
+                            true => RunnerReply::new(&request_id, RunnerReplyType::Accepted, None),
+                            false => RunnerReply::new(&request_id, RunnerReplyType::Rejected, Some("No targeted scan queued or active".to_string()))
+                        };
+                        send_reply(&mut websocket, &reply);
+                    }
+                },
+                Err((request_id, err)) => {
+                    error!("Could not read text message from socket: {}", err);
+                    if let Some(request_id) = request_id {
+                        send_reply(&mut websocket, &RunnerReply::new(&request_id, RunnerReplyType::Rejected, Some(format!("{:#}", err))));
+                    }
+                }
+            }
+        }
+    });
+
+    ControlChannel {
+        requests: receiver,
+        replies: thread_replies,
+        pending: vec![],
+        cancel_signal: channel_cancel_signal
     }
-    let raw_message = socket_read_result.unwrap_or_else(|err| {
-        error!("Expected a valid message, internal logic error ({})", err);
-        process::exit(1);
+}
+
+/// Cancellations targeting a scan request apply to the whole request, otherwise
+/// only the scan running on the repository is stopped
+fn apply_cancellation(cancel_request: &CancelRequest, cancel_signal: &CancelSignal) -> bool {
+
+    match (&cancel_request.scan_request_id, &cancel_request.repository_id) {
+        (Some(scan_request_id), _) => {
+            let is_cancelled = cancel_signal.cancel_request(scan_request_id);
+            match is_cancelled {
+                true => info!("Received cancellation for scan request {}", scan_request_id),
+                false => warn!("Received cancellation for scan request {} but it is neither queued nor active", scan_request_id)
+            }
+            is_cancelled
+        },
+        (None, Some(repository_id)) => {
+            let is_cancelled = cancel_signal.request(repository_id);
+            match is_cancelled {
+                true => info!("Received cancellation for scan on repository ID {}", repository_id),
+                false => warn!("Received cancellation for repository ID {} but no scan is active", repository_id)
+            }
+            is_cancelled
+        },
+        (None, None) => false
+    }
+}
+
+fn send_reply(websocket: &mut Ws, reply: &RunnerReply) {
+
+    let reply_message = match serde_json::to_string(reply) {
+        Ok(reply_message) => reply_message,
+        Err(err) => {
+            error!("Could not encode reply for request {} ({})", reply.request_id, err);
+            return;
+        }
+    };
+    websocket.send(tungstenite::Message::Text(reply_message)).unwrap_or_else(|err| {
+        error!("Could not send reply for request {} ({})", reply.request_id, err);
     });
+}
 
-    let decode_result = decode_message(raw_message);
-    if let Err(err) = decode_result {
-        error!("Could not read text message from socket: {}", err);
-        return Ok(()); 
+fn process_message(control_channel: &mut ControlChannel, shared_config: Rc<Config>, scheduler: Rc<Scheduler>, workspace: Rc<Workspace>, tracker: Rc<ContainerTracker>, egress_proxy: Option<Rc<EgressProxy>>) -> Result<(), Error> {
+
+    let request = control_channel.next_request();
+    info!("Received request for repository IDs {} (functions {})", request.repositories.join(","), request.functions.join(","));
+    control_channel.reply(&request, RunnerReplyType::Started, None);
+
+    let request_result = scan_repositories(&request, shared_config.clone(), &scheduler, &workspace, &tracker, egress_proxy.as_deref());
+
+    let is_cancelled = request.request_id.as_deref().is_some_and(|request_id| tracker.cancel_signal().is_cancelled(request_id));
+    match &request_result {
+        Ok(()) if is_cancelled => control_channel.reply(&request, RunnerReplyType::Cancelled, None),
+        Ok(()) => control_channel.reply(&request, RunnerReplyType::Completed, None),
+        Err(err) => control_channel.reply(&request, RunnerReplyType::Failed, Some(format!("{:#}", err)))
     }
-    let request = decode_result.unwrap_or_else(|err| {
-        error!("Expected a decoded message, internal logic error ({})", err);
-        process::exit(1);
+    if let Some(request_id) = &request.request_id {
+        tracker.cancel_signal().finish(request_id);
+    }
+    request_result?;
+
+    workspace.prune_storage()?;
+
+    apply_image_retention(&shared_config, &scheduler, &workspace);
+
+    Ok(())
+}
+
+/// Apply the image retention policy, skipped when the functions that protect
+/// their images from removal cannot be retrieved
+fn apply_image_retention(config: &Config, scheduler: &Scheduler, workspace: &Workspace) {
+
+    if !config.container.retention.is_enabled() {
+        return;
+    }
+
+    let code_functions = match scheduler.get_functions(&[]) {
+        Ok(code_functions) => code_functions,
+        Err(err) => {
+            warn!("Could not retrieve functions, image retention policy skipped ({:#})", err);
+            return;
+        }
+    };
+
+    collect_images(&config.container, workspace.get_path(), &code_functions).unwrap_or_else(|err| {
+        warn!("Could not apply image retention policy ({})", err);
     });
-    let repository_id = if let Some(repository_id) = request.repositories.first() {
-        repository_id
+}
+
+/// Functions and reference applied to every repository of a scan request
+struct ScanPlan<'a> {
+    code_functions: Vec<CodeFunction>,
+    git_ref: Option<&'a str>
+}
+
+/// Run the requested functions over each repository in turn, a repository
+/// that could not be scanned is reported as failed scans before moving on
+fn scan_repositories(request: &ScanRequest, shared_config: Rc<Config>, scheduler: &Scheduler, workspace: &Workspace, tracker: &ContainerTracker, egress_proxy: Option<&EgressProxy>) -> Result<(), Error> {
+
+    let mut code_functions = scheduler.get_functions(&request.functions).context("Failure when retrieving functions")?;
+    if let Some(timeout) = request.options.timeout {
+        for code_function in code_functions.iter_mut() {
+            code_function.stage_timeout = Some(timeout);
+        }
     }
-    else {
-        error!("Could not find repository to scan");
-        return Ok(());  
+    let scan_plan = ScanPlan {
+        code_functions,
+        git_ref: request.git_ref.as_deref()
     };
-    info!("Received request for repository ID {} (functions {})", repository_id, request.functions.join(","));
 
-    let code_functions = scheduler.get_functions(&request.functions).context("Failure when retrieving functions")?;
-    let repository = scheduler.get_repository(repository_id).context("Failure when retrieving repository")?;
+    let repository_ids = match requested_repositories(&request.repositories) {
+        Some(repository_ids) => repository_ids,
+        None => scheduler.get_repositories().context("Failure when retrieving repositories")?
+            .into_iter()
+            .map(|repository| repository.id)
+            .collect()
+    };
+    if repository_ids.is_empty() {
+        warn!("No repository to scan for this request");
+    }
+
+    for (position, repository_id) in repository_ids.iter().enumerate() {
+
+        if request.request_id.as_deref().is_some_and(|request_id| tracker.cancel_signal().is_cancelled(request_id)) {
+            warn!("Scan request cancelled, skipping {} remaining repositories", repository_ids.len() - position);
+            break;
+        }
+
+        info!("Scanning repository ID {} ({}/{})", repository_id, position + 1, repository_ids.len());
+
+        tracker.cancel_signal().begin(repository_id, request.request_id.as_deref());
+        let scan_result = scan_repository(repository_id, &scan_plan, shared_config.clone(), scheduler, workspace, tracker, egress_proxy);
+        tracker.cancel_signal().end(repository_id);
+
+        if let Err(err) = scan_result {
+            let error_message = format!("Could not scan repository ({:#})", err);
+            report_failed_scans(scheduler, &scan_plan.code_functions, repository_id, FailureReason::RunnerInternalError, &error_message);
+        }
+    }
+
+    Ok(())
+}
+
+/// Requested repository IDs without duplicates, none when the wildcard selects every repository
+fn requested_repositories(repositories: &[String]) -> Option<Vec<String>> {
+
+    if repositories.iter().any(|repository_id| repository_id == "*") {
+        return None;
+    }
+
+    let mut repository_ids: Vec<String> = vec![];
+    for repository_id in repositories {
+        if !repository_ids.contains(repository_id) {
+            repository_ids.push(repository_id.clone());
+        }
+    }
+    Some(repository_ids)
+}
+
+/// Scan a repository with every function of the plan, failures are submitted as
+/// failed scans here and errors are only returned before any scan was submitted
+fn scan_repository(repository_id: &str, scan_plan: &ScanPlan, shared_config: Rc<Config>, scheduler: &Scheduler, workspace: &Workspace, tracker: &ContainerTracker, egress_proxy: Option<&EgressProxy>) -> Result<(), Error> {
+
+    let code_functions = &scan_plan.code_functions;
+
+    let repository = match scheduler.get_repository(repository_id) {
+        Ok(repository) => repository,
+        Err(err) => {
+            let error_message = format!("Could not retrieve repository ({:#})", err);
+            report_failed_scans(scheduler, code_functions, repository_id, FailureReason::RunnerInternalError, &error_message);
+            return Ok(());
+        }
+    };
+
+    workspace.prune_storage()?;
+    if workspace.is_full()? {
+        let error_message = format!("Workspace is full ({}Mb limit)", shared_config.workspace.cache_limit);
+        report_failed_scans(scheduler, code_functions, &repository.id, FailureReason::RunnerInternalError, &error_message);
+        return Ok(());
+    }
 
     workspace.clean(&repository.id, false)?;
 
-    info!("Starting functions on repository {} with ID {} ({:?}, {:?})", repository.name, repository.id, repository.branch, repository.directory);
+    let branch = scan_plan.git_ref.or(repository.branch.as_deref());
+    info!("Starting functions on repository {} with ID {} ({:?}, {:?})", repository.name, repository.id, branch, repository.directory);
     
-    let last_commit = repository.pull_or_clone(shared_config.clone())?;
+    let last_commit = match repository.pull_or_clone(shared_config.clone(), scan_plan.git_ref) {
+        Ok(last_commit) => last_commit,
+        Err(err) => {
+            let error_message = format!("Could not clone or pull repository ({:#})", err);
+            report_failed_scans(scheduler, code_functions, &repository.id, FailureReason::CloneError, &error_message);
+            return Ok(());
+        }
+    };
+
+    let (suppressions, repository_gates) = read_repository_config(workspace, &repository.id);
 
     for code_function in code_functions.iter() {
 
         info!("Executing function \"{}\" (ID {})", code_function.name, code_function.public_id);
 
-        let finished_scan = run_container(shared_config.clone(), &workspace, &repository.id , code_function, last_commit.clone())?;
-        let scan_id = scheduler.store_scan(finished_scan)?;
-        process_issues(&workspace, &repository.id, &scheduler, &code_function.public_id, &scan_id)?;
+        let mut finished_scan = run_container(shared_config.clone(), workspace, tracker, egress_proxy, &repository.id , code_function, last_commit.clone())
+            .unwrap_or_else(|err| {
+                error!("Function \"{}\" failed due to runner error: {:#}", code_function.name, err);
+                Scan::failed(&code_function.public_id, &repository.id, Some(last_commit.clone()), FailureReason::RunnerInternalError, format!("{:#}", err))
+            });
+
+        // Results are read before submitting the scan so that their problems appear in the scan logs
+        let result_reader = ResultReader::new(workspace.get_result_path(&repository.id), &shared_config.workspace);
+        let mut result_problems: Vec<String> = vec![];
+
+        finished_scan.results = read_metrics(&result_reader, &code_function.outputs, &mut result_problems);
+        let mut issues = read_issues(&result_reader, code_function, &mut result_problems);
+        let sboms = read_sboms(&result_reader, &mut result_problems);
+        finished_scan.results.extend(sbom_metrics(&sboms));
+
+        report_problems(&mut finished_scan, code_function, &result_problems);
+        limit_results(&shared_config.workspace, &mut finished_scan, &mut issues);
+        if let Some(issue_list) = issues.as_mut() {
+            annotate_issues(workspace, &repository.id, &suppressions, issue_list);
+        }
+
+        if !finished_scan.is_cancelled {
+            let gates = select_gates(code_function, &repository_gates);
+            let violations = evaluate_gates(&gates, issues.as_deref().unwrap_or_default(), &finished_scan.results);
+            report_violations(&mut finished_scan, violations);
+        }
+
+        let updated_index = diff_issues(workspace, &repository.id, scan_plan.git_ref, code_function, &mut finished_scan, issues.as_mut(), result_problems.is_empty());
+        let issue_list = issues.unwrap_or_default();
+
+        let artifacts = collect_scan_artifacts(&shared_config, workspace, &repository.id, code_function, &mut finished_scan);
+
+        let is_cancelled = finished_scan.is_cancelled;
+        let scan_id = match scheduler.store_scan(finished_scan) {
+            Ok(scan_id) => scan_id,
+            Err(err) => {
+                error!("Could not submit scan of function \"{}\" ({:#})", code_function.name, err);
+                continue;
+            }
+        };
+
+        // Remaining functions are skipped and partial results are dropped
+        if is_cancelled {
+            warn!("Scan on repository ID {} cancelled, skipping remaining functions", repository.id);
+            break;
+        }
+        store_issues(&repository.id, scheduler, code_function, &scan_id, issue_list).unwrap_or_else(|err| {
+            error!("Could not submit issues of function \"{}\" ({:#})", code_function.name, err);
+        });
+
+        if let Some(issue_index) = updated_index {
+            issue_index.save(workspace, &code_function.public_id, &repository.id, scan_plan.git_ref).unwrap_or_else(|err| {
+                warn!("Could not save issue index of function \"{}\" ({:#})", code_function.name, err);
+            });
+        }
+
+        for sbom in sboms {
+            info!("Submitting {} components from {:?} SBOM {}", sbom.components.len(), sbom.format, sbom.file);
+            scheduler.store_components(&scan_id, &sbom).unwrap_or_else(|err| {
+                warn!("Could not submit SBOM components of function \"{}\" ({:#})", code_function.name, err);
+            });
+        }
+
+        for artifact in artifacts {
+            info!("Uploading artifact {} ({} bytes, {} compressed)", artifact.path, artifact.size, artifact.compressed_content.len());
+            scheduler.store_artifact(&scan_id, &artifact).unwrap_or_else(|err| {
+                warn!("Could not upload artifact {} of function \"{}\" ({:#})", artifact.path, code_function.name, err);
+            });
+        }
     }
 
-    workspace.clean(&repository.id, false)?;
-    workspace.prune_storage()?;
+    workspace.clean(&repository.id, false).unwrap_or_else(|err| {
+        warn!("Could not clean workspace of repository ID {} ({:#})", repository.id, err);
+    });
+
+    Ok(())
+}
+
+/// Collect the artifacts declared by a function, skipped files are reported in the scan logs
+fn collect_scan_artifacts(config: &Config, workspace: &Workspace, repository_id: &str, code_function: &CodeFunction, scan: &mut Scan) -> Vec<Artifact> {
+
+    if scan.is_cancelled {
+        return vec![];
+    }
+
+    let result_path = workspace.get_result_path(repository_id);
+    let collected_artifacts = collect_artifacts(&result_path, &code_function.artifacts, config.workspace.artifact_file_limit, config.workspace.artifact_total_limit);
+
+    match collected_artifacts {
+        Ok((artifacts, messages)) => {
+            for message in messages {
+                warn!("Function \"{}\": {}", code_function.name, message);
+                scan.logs.push_str(&format!("[chicon] {}\n", message));
+            }
+            artifacts
+        },
+        Err(err) => {
+            warn!("Could not collect artifacts of function \"{}\" ({:#})", code_function.name, err);
+            vec![]
+        }
+    }
+}
+
+/// Submit failed scans for a repository, submission errors are only logged so
+/// that callers never report the same failure twice
+fn report_failed_scans(scheduler: &Scheduler, code_functions: &[CodeFunction], repository_id: &str, failure_reason: FailureReason, error_message: &str) {
+
+    submit_failed_scans(scheduler, code_functions, repository_id, failure_reason, error_message).unwrap_or_else(|err| {
+        error!("Could not submit failed scans for repository ID {} ({:#})", repository_id, err);
+    });
+}
+
+/// Report a failure on every requested function when no function could run, a
+/// submission error does not prevent the remaining functions from being reported
+fn submit_failed_scans(scheduler: &Scheduler, code_functions: &[CodeFunction], repository_id: &str, failure_reason: FailureReason, error_message: &str) -> Result<(), Error> {
+
+    error!("Scan failed on repository ID {}: {}", repository_id, error_message);
+
+    let mut failed_functions: Vec<&str> = vec![];
+    for code_function in code_functions {
+
+        let failed_scan = Scan::failed(&code_function.public_id, repository_id, None, failure_reason, error_message.to_string());
+        if let Err(err) = scheduler.store_scan(failed_scan) {
+            error!("Could not submit failed scan of function \"{}\" ({:#})", code_function.name, err);
+            failed_functions.push(&code_function.name);
+        }
+    }
+
+    if !failed_functions.is_empty() {
+        bail!("Could not submit failed scans of {} functions ({})", failed_functions.len(), failed_functions.join(", "));
+    }
+
+    Ok(())
+}
+
+/// Decodes any message received by the control plane, cancellations use
+/// the "cancel;[repository]" format and v2 messages are JSON objects. The
+/// request ID is returned with errors when it could be read
+fn decode_runner_message(raw_message: tungstenite::Message, protocol: ProtocolVersion) -> Result<RunnerMessage, (Option<String>, Error)> {
+
+    if let Ok(runner_command) = raw_message.to_text() {
+
+        if runner_command.trim_start().starts_with('{') {
+            if protocol != ProtocolVersion::V2 {
+                return Err((None, Error::msg("JSON messages require the v2 protocol")));
+            }
+            return decode_protocol_message(runner_command);
+        }
+
+        if let Some(repository_raw) = runner_command.strip_prefix("cancel;") {
+
+            let repository_id = repository_raw.trim();
+            if repository_id.is_empty() || repository_id.contains(';') {
+                return Err((None, Error::msg("Expected a single repository identifier in cancel message")));
+            }
+            return Ok(RunnerMessage::Cancel(CancelRequest {
+                request_id: None,
+                repository_id: Some(repository_id.to_string()),
+                scan_request_id: None
+            }));
+        }
+    }
+
+    decode_message(raw_message)
+        .map(RunnerMessage::Scan)
+        .map_err(|err| (None, err))
+}
+
+/// Decodes a v2 JSON message, see the README for the format
+fn decode_protocol_message(runner_command: &str) -> Result<RunnerMessage, (Option<String>, Error)> {
+
+    debug!("Received command '{}'", runner_command);
+
+    let message: ProtocolMessage = match serde_json::from_str(runner_command) {
+        Ok(message) => message,
+        Err(err) => {
+            // The request ID is still echoed when another field is invalid
+            let request_id = serde_json::from_str::<serde_json::Value>(runner_command).ok()
+                .and_then(|value| value.get("requestId").and_then(|id| id.as_str()).map(|id| id.to_string()));
+            return Err((request_id, Error::new(err).context("Invalid v2 message")));
+        }
+    };
+
+    let request_id = message.request_id.trim().to_string();
+    if request_id.is_empty() {
+        return Err((None, Error::msg("Expected a non-empty request identifier")));
+    }
+
+    validate_protocol_message(&message)
+        .map_err(|err| (Some(request_id.clone()), err))?;
+
+    let repositories: Vec<String> = message.repositories.iter()
+        .map(|repository| repository.trim().to_string())
+        .collect();
+
+    match message.message_type {
+        ProtocolMessageType::Cancel => Ok(RunnerMessage::Cancel(CancelRequest {
+            request_id: Some(request_id),
+            repository_id: repositories.into_iter().next(),
+            scan_request_id: message.scan_request_id.map(|scan_request_id| scan_request_id.trim().to_string())
+        })),
+        ProtocolMessageType::Scan => Ok(RunnerMessage::Scan(ScanRequest {
+            _version: message.version,
+            request_id: Some(request_id),
+            repositories,
+            functions: message.functions.iter().map(|function| function.trim().to_string()).collect(),
+            git_ref: message.git_ref.map(|git_ref| git_ref.trim().to_string()),
+            options: message.options
+        }))
+    }
+}
+
+fn validate_protocol_message(message: &ProtocolMessage) -> Result<(), Error> {
+
+    if message.version != "v2" {
+        bail!("Expected 'v2' JSON message");
+    }
+    if message.repositories.iter().any(|repository| repository.trim().is_empty()) {
+        bail!("Expected non-empty repository identifiers or wildcard");
+    }
+
+    match message.message_type {
+        ProtocolMessageType::Cancel => {
+            if message.scan_request_id.as_ref().is_some_and(|scan_request_id| scan_request_id.trim().is_empty()) {
+                bail!("Expected a non-empty scan request identifier");
+            }
+            // The repository is optional when the whole scan request is cancelled
+            if message.repositories.len() > 1 || (message.repositories.is_empty() && message.scan_request_id.is_none()) {
+                bail!("Expected a single repository identifier in cancel message");
+            }
+        },
+        ProtocolMessageType::Scan => {
+            if message.repositories.is_empty() {
+                bail!("Expected non-empty repository identifiers or wildcard");
+            }
+            if message.functions.is_empty() || message.functions.iter().any(|function| function.trim().is_empty()) {
+                bail!("Expected non-empty function identifiers or wildcard");
+            }
+            if message.git_ref.as_ref().is_some_and(|git_ref| git_ref.trim().is_empty()) {
+                bail!("Expected a non-empty reference");
+            }
+            if message.options.timeout == Some(0) {
+                bail!("Expected a timeout of at least one second");
+            }
+        }
+    }
 
     Ok(())
 }
@@ -178,8 +849,14 @@ fn decode_message(raw_message: tungstenite::Message) -> Result<ScanRequest, Erro
     let repository_raw = message_parts.get(1).map(|message| message.trim());
     let repository_message = match repository_raw {
         Some(m) if !m.is_empty() => m,
-        _ => bail!("Expected a non-empty repository identifier or wildcard"),
+        _ => bail!("Expected non-empty repository identifiers or wildcard"),
     };
+    let repositories: Vec<String> = repository_message.split(',')
+        .map(|m| m.trim().to_string())
+        .collect();
+    if repositories.iter().any(|repository_id| repository_id.is_empty()) {
+        bail!("Expected non-empty repository identifiers or wildcard");
+    }
 
     let function_raw = message_parts.get(2).map(|message| message.trim());
     let function_message = match function_raw {
@@ -192,59 +869,206 @@ fn decode_message(raw_message: tungstenite::Message) -> Result<ScanRequest, Erro
 
     let scan_request = ScanRequest {
         _version: version,
-        repositories: vec![
-            repository_message.into()
-        ],
-        functions
+        repositories,
+        functions,
+        ..Default::default()
     };
     Ok(scan_request)
 }
 
-fn process_issues(workspace: &Workspace, repository_id: &str, scheduler: &Scheduler, function_id: &str, scan_id: &str) -> Result<(), Error> {
+/// Read issues from result/issues.toml and from scanner reports
+fn read_issues(result_reader: &ResultReader, code_function: &CodeFunction, problems: &mut Vec<String>) -> Option<Vec<CodeIssue>> {
+
+    let potential_issues = read_issue_file(result_reader, problems);
+    let potential_reports = read_reports(result_reader, code_function, problems);
+
+    if potential_issues.is_none() && potential_reports.is_none() {
+        return None;
+    }
+
+    let mut issue_list = potential_issues.unwrap_or_default();
+    issue_list.extend(potential_reports.unwrap_or_default());
+    Some(issue_list)
+}
+
+fn store_issues(repository_id: &str, scheduler: &Scheduler, code_function: &CodeFunction, scan_id: &str, issue_list: Vec<CodeIssue>) -> Result<(), Error> {
+
+    if issue_list.is_empty() {
+        info!("No issues found linked to repository ID {}", repository_id);
+        return Ok(());
+    }
+
+    let formatted_issues = issue_list.into_iter()
+        .map(|issue_item| CodeIssue {
+            scan_id: Some(scan_id.to_string()),
+            repository_id: Some(repository_id.to_string()),
+            function_id: Some(code_function.public_id.to_string()),
+            ..issue_item
+        })
+        .collect();
+    scheduler.store_issue(formatted_issues)?;
+
+    Ok(())
+}
+
+/// Classify issues against the last successful scan of the function, resolved issues
+/// are only reported when the scan results are complete and every result file was
+/// valid. The updated index is returned for complete scans, nothing is compared when
+/// the function wrote no issue file or report.
+fn diff_issues(workspace: &Workspace, repository_id: &str, git_ref: Option<&str>, code_function: &CodeFunction, scan: &mut Scan, issues: Option<&mut Vec<CodeIssue>>, has_valid_files: bool) -> Option<IssueIndex> {
+
+    // Without an issue file or report, previous issues would all look resolved
+    let issues = issues?;
+
+    let previous_index = IssueIndex::load(workspace, &code_function.public_id, repository_id, git_ref).unwrap_or_else(|err| {
+        warn!("Could not load issue index of function \"{}\" ({:#})", code_function.name, err);
+        None
+    });
+
+    let has_valid_results = scan.failure_reason.map_or(true, |failure_reason| failure_reason == FailureReason::QualityGateFailed);
+    let is_complete = has_valid_files && has_valid_results && !scan.is_cancelled && !scan.is_truncated;
+
+    let resolved_issues = previous_index.unwrap_or_default().diff(issues);
+    if is_complete {
+        issues.extend(resolved_issues);
+    }
+    scan.results.push(diff_metric(issues));
+
+    is_complete.then(|| IssueIndex::from_issues(issues))
+}
+
+/// Read the suppressions and quality gates of the repository (.chicon.toml), invalid entries are logged
+fn read_repository_config(workspace: &Workspace, repository_id: &str) -> (Suppressions, Vec<QualityGate>) {
+
+    let config_content = match workspace.read_repository_file(repository_id, REPOSITORY_CONFIG_FILE) {
+        Ok(Some(config_content)) => config_content,
+        Ok(None) => return (Suppressions::default(), vec![]),
+        Err(err) => {
+            warn!("Could not read {} of repository ID {} ({:#})", REPOSITORY_CONFIG_FILE, repository_id, err);
+            return (Suppressions::default(), vec![]);
+        }
+    };
+
+    let suppressions = match Suppressions::parse(&config_content, current_date()) {
+        Ok((suppressions, messages)) => {
+            for message in messages {
+                warn!("Repository ID {}: {}", repository_id, message);
+            }
+            suppressions
+        },
+        Err(err) => {
+            warn!("Could not parse suppressions in {} of repository ID {} ({})", REPOSITORY_CONFIG_FILE, repository_id, err);
+            Suppressions::default()
+        }
+    };
+
+    let gates = parse_repository_gates(&config_content).unwrap_or_else(|err| {
+        warn!("Could not parse quality gates in {} of repository ID {} ({})", REPOSITORY_CONFIG_FILE, repository_id, err);
+        vec![]
+    });
+
+    (suppressions, gates)
+}
+
+/// Fingerprint issues and flag the suppressed ones, suppressed issues are still submitted
+fn annotate_issues(workspace: &Workspace, repository_id: &str, suppressions: &Suppressions, issues: &mut [CodeIssue]) {
+
+    for issue in issues.iter_mut() {
 
-    let potential_issues = workspace.read_string(repository_id, "result/issues.toml");
+        if issue.fingerprint.is_none() {
+            issue.fingerprint = Some(compute_issue_fingerprint(workspace, repository_id, issue));
+        }
 
-    if let Ok(vulnerabilities_content) = potential_issues {
+        let source_line = match (&issue.path, issue.start_line) {
+            (Some(path), Some(start_line)) => workspace.read_source_lines(repository_id, path, start_line, start_line).ok(),
+            _ => None
+        };
+        suppressions.apply(issue, source_line.as_deref());
+    }
+}
 
-        let mut issue_list: Vec<CodeIssue> = vec![];
-        let parsed_toml: Result<IssueContainer, toml::de::Error> = toml::from_str(&vulnerabilities_content);
+/// Fingerprint an issue from its snippet, the snippet is read from the repository
+/// checkout when the function only provides a location
+fn compute_issue_fingerprint(workspace: &Workspace, repository_id: &str, issue: &CodeIssue) -> String {
 
-        match parsed_toml {
-            Ok(toml_results) => {
-                issue_list = toml_results.issues;
+    let source_snippet = match (&issue.snippet, &issue.path, issue.start_line) {
+        (Some(snippet), _, _) => Some(snippet.to_string()),
+        (None, Some(path), Some(start_line)) => {
+            let end_line = issue.end_line.unwrap_or(start_line);
+            workspace.read_source_lines(repository_id, path, start_line, end_line).ok()
+        },
+        _ => None
+    };
+
+    issue.compute_fingerprint(source_snippet.as_deref())
+}
+
+/// Parse scanner reports declared by the function, reports are detected in the
+/// result directory when the function does not declare any
+fn read_reports(result_reader: &ResultReader, code_function: &CodeFunction, problems: &mut Vec<String>) -> Option<Vec<CodeIssue>> {
+
+    let declared_reports: Vec<(String, Option<ReportFormat>)> = if code_function.reports.is_empty() {
+        result_reader.list_files().into_iter()
+            .filter(|file_name| file_name.ends_with(".json") || file_name.ends_with(".sarif"))
+            .filter(|file_name| !METRICS_FILES.contains(&file_name.as_str()) && !ISSUES_FILES.contains(&file_name.as_str()))
+            .map(|file_name| (file_name, None))
+            .collect()
+    }
+    else {
+        code_function.reports.iter()
+            .map(|report| (report.file.to_string(), report.format))
+            .collect()
+    };
+
+    let mut report_issues: Option<Vec<CodeIssue>> = None;
+
+    for (file_name, potential_format) in declared_reports {
+
+        let report_content = match result_reader.read_file(&file_name) {
+            Ok(Some(report_content)) => report_content,
+            Ok(None) => {
+                if !code_function.reports.is_empty() {
+                    problems.push(format!("result/{}: declared report not found", file_name));
+                }
+                continue;
             },
             Err(err) => {
-                error!("Could not parse issue TOML for repository {}", repository_id);
-                dbg!(err);
+                problems.push(format!("result/{}: file refused, {:#}", file_name, err));
+                continue;
             }
         };
 
-        let formatted_issues = issue_list.into_iter()
-            .map(|issue_item| {
-                CodeIssue {
-                    name: issue_item.name,
-                    scan_id: Some(scan_id.to_string()),
-                    severity: issue_item.severity,
-                    repository_id: Some(repository_id.to_string()),
-                    function_id: Some(function_id.to_string())
+        let format = match potential_format.or_else(|| detect_format(&report_content)) {
+            Some(format) => format,
+            None => {
+                if !code_function.reports.is_empty() {
+                    problems.push(format!("result/{}: unknown report format", file_name));
                 }
-            })
-            .collect();
-        scheduler.store_issue(formatted_issues)?;
-    }
-    else {
-        info!("No issues found linked to repository ID {}", repository_id);
+                continue;
+            }
+        };
+
+        match parse_report(format, &report_content) {
+            Ok(issues) => {
+                info!("Parsed {} issues from {:?} report {}", issues.len(), format, file_name);
+                report_issues.get_or_insert_with(Vec::new).extend(issues);
+            },
+            Err(err) => problems.push(format!("result/{}: could not parse {:?} report ({})", file_name, format, err))
+        }
     }
 
-    Ok(())
+    report_issues
 }
 
 #[cfg(test)]
+#[allow(clippy::redundant_pattern_matching)]
 mod tests {
 
+    use std::sync::mpsc;
     use anyhow::Error;
     use tungstenite::Message;
-    use super::{decode_message, ScanRequest};
+    use crate::components::config::ProtocolVersion;
+    use super::{decode_message, CancelRequest, CancelSignal, ControlChannel, decode_runner_message, requested_repositories, select_request, RunnerMessage, RunnerReply, RunnerReplyType, ScanOptions, ScanRequest};
 
     #[test]
     fn should_decode_basic_message() -> Result<(), Error> {
@@ -254,6 +1078,7 @@ mod tests {
             _version: "v1".to_string(),
             repositories: vec!["7b2c112a-f7e5-4106-bffe-4734eb4fe49a".into()],
             functions: vec!["4ed8e41b-d226-4b4c-a55c-e22099173730".into()],
+            ..Default::default()
         };
 
         let decoded_message = decode_message(message)?;
@@ -275,6 +1100,27 @@ mod tests {
                 "4ed8e41b-d226-4b4c-a55c-e22099173730".into(),
                 "aebe69bd-5245-4dff-aa0b-d7cbb6a4efdf".into()
             ],
+            ..Default::default()
+        };
+
+        let decoded_message = decode_message(message)?;
+        assert_eq!(expected_request, decoded_message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decode_multi_repository_message() -> Result<(), Error> {
+
+        let message = Message::text("v1;7b2c112a-f7e5-4106-bffe-4734eb4fe49a, 0c1f3e9d-58a2-4f39-9c3e-2d7a6b41e8f0;*");
+        let expected_request = ScanRequest {
+            _version: "v1".to_string(),
+            repositories: vec![
+                "7b2c112a-f7e5-4106-bffe-4734eb4fe49a".into(),
+                "0c1f3e9d-58a2-4f39-9c3e-2d7a6b41e8f0".into()
+            ],
+            functions: vec!["*".into()],
+            ..Default::default()
         };
 
         let decoded_message = decode_message(message)?;
@@ -283,6 +1129,24 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn should_reject_empty_repository_in_list() {
+
+        let message = Message::text("v1;7b2c112a-f7e5-4106-bffe-4734eb4fe49a,;*");
+
+        assert!(decode_message(message).is_err());
+    }
+
+    #[test]
+    fn should_deduplicate_requested_repositories() {
+
+        let repositories = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+        assert_eq!(Some(vec!["a".to_string(), "b".to_string()]), requested_repositories(&repositories));
+
+        let repositories = vec!["a".to_string(), "*".to_string()];
+        assert_eq!(None, requested_repositories(&repositories));
+    }
+
     #[test]
     fn should_decode_wildcard_function_message() -> Result<(), Error> {
 
@@ -293,6 +1157,7 @@ mod tests {
                 "7b2c112a-f7e5-4106-bffe-4734eb4fe49a".into(),
             ],
             functions: vec!["*".into()],
+            ..Default::default()
         };
 
         let decoded_message = decode_message(message)?;
@@ -393,4 +1258,168 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn should_decode_cancel_message() -> Result<(), Error> {
+
+        let message = Message::text("cancel;7b2c112a-f7e5-4106-bffe-4734eb4fe49a");
+        let expected_message = RunnerMessage::Cancel(CancelRequest {
+            request_id: None,
+            repository_id: Some("7b2c112a-f7e5-4106-bffe-4734eb4fe49a".into()),
+            scan_request_id: None
+        });
+
+        assert_eq!(expected_message, decode_runner_message(message, ProtocolVersion::V1).map_err(|(_, err)| err)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_empty_cancel_message() {
+
+        let message = Message::text("cancel; ");
+
+        assert!(decode_runner_message(message, ProtocolVersion::V1).is_err());
+    }
+
+    #[test]
+    fn should_decode_v2_scan_message() -> Result<(), Error> {
+
+        let message = Message::text(r#"{
+            "version": "v2",
+            "type": "scan",
+            "requestId": "req-1",
+            "repositories": ["7b2c112a-f7e5-4106-bffe-4734eb4fe49a"],
+            "functions": ["4ed8e41b-d226-4b4c-a55c-e22099173730"],
+            "ref": "release/1.2",
+            "options": {"timeout": 600, "priority": 5}
+        }"#);
+        let expected_request = ScanRequest {
+            _version: "v2".to_string(),
+            request_id: Some("req-1".into()),
+            repositories: vec!["7b2c112a-f7e5-4106-bffe-4734eb4fe49a".into()],
+            functions: vec!["4ed8e41b-d226-4b4c-a55c-e22099173730".into()],
+            git_ref: Some("release/1.2".into()),
+            options: ScanOptions { timeout: Some(600), priority: 5 }
+        };
+
+        let decoded_message = decode_runner_message(message, ProtocolVersion::V2).map_err(|(_, err)| err)?;
+        assert_eq!(RunnerMessage::Scan(expected_request), decoded_message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decode_v2_cancel_message() -> Result<(), Error> {
+
+        let message = Message::text(r#"{"version":"v2","type":"cancel","requestId":"req-2","repositories":["7b2c112a-f7e5-4106-bffe-4734eb4fe49a"],"scanRequestId":"req-1"}"#);
+        let expected_message = RunnerMessage::Cancel(CancelRequest {
+            request_id: Some("req-2".into()),
+            repository_id: Some("7b2c112a-f7e5-4106-bffe-4734eb4fe49a".into()),
+            scan_request_id: Some("req-1".into())
+        });
+
+        assert_eq!(expected_message, decode_runner_message(message, ProtocolVersion::V2).map_err(|(_, err)| err)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_decode_v2_request_cancel_message() -> Result<(), Error> {
+
+        let message = Message::text(r#"{"version":"v2","type":"cancel","requestId":"req-2","scanRequestId":"req-1"}"#);
+        let expected_message = RunnerMessage::Cancel(CancelRequest {
+            request_id: Some("req-2".into()),
+            repository_id: None,
+            scan_request_id: Some("req-1".into())
+        });
+
+        assert_eq!(expected_message, decode_runner_message(message, ProtocolVersion::V2).map_err(|(_, err)| err)?);
+
+        let message = Message::text(r#"{"version":"v2","type":"cancel","requestId":"req-2"}"#);
+        assert!(decode_runner_message(message, ProtocolVersion::V2).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_v1_messages_with_v2_protocol() {
+
+        let message = Message::text("v1;7b2c112a-f7e5-4106-bffe-4734eb4fe49a;*");
+
+        assert!(decode_runner_message(message, ProtocolVersion::V2).is_ok());
+    }
+
+    #[test]
+    fn should_reject_v2_message_with_v1_protocol() {
+
+        let message = Message::text(r#"{"version":"v2","type":"scan","requestId":"req-1","repositories":["a"],"functions":["*"]}"#);
+
+        assert!(decode_runner_message(message, ProtocolVersion::V1).is_err());
+    }
+
+    #[test]
+    fn should_return_request_id_of_invalid_v2_message() {
+
+        let message = Message::text(r#"{"version":"v2","type":"scan","requestId":"req-3","repositories":["a"],"functions":[]}"#);
+        let (request_id, _) = decode_runner_message(message, ProtocolVersion::V2).expect_err("Functions should be required");
+        assert_eq!(Some("req-3".to_string()), request_id);
+
+        let message = Message::text(r#"{"version":"v2","type":"rebuild","requestId":"req-4","repositories":["a"]}"#);
+        let (request_id, _) = decode_runner_message(message, ProtocolVersion::V2).expect_err("Unknown types should be rejected");
+        assert_eq!(Some("req-4".to_string()), request_id);
+
+        let message = Message::text(r#"{"version":"v2","type":"scan","repositories":["a"],"functions":["*"]}"#);
+        let (request_id, _) = decode_runner_message(message, ProtocolVersion::V2).expect_err("Request ID should be required");
+        assert_eq!(None, request_id);
+    }
+
+    #[test]
+    fn should_encode_reply() -> Result<(), Error> {
+
+        let reply = RunnerReply::new("req-1", RunnerReplyType::Failed, Some("Clone failed".into()));
+        assert_eq!(r#"{"version":"v2","type":"failed","requestId":"req-1","error":"Clone failed"}"#, serde_json::to_string(&reply)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_select_highest_priority_first() {
+
+        let request = |request_id: &str, priority: i64| ScanRequest {
+            request_id: Some(request_id.into()),
+            options: ScanOptions { timeout: None, priority },
+            ..Default::default()
+        };
+        let mut pending = vec![request("low", 0), request("high", 5), request("other-high", 5)];
+
+        let selected: Vec<Option<String>> = std::iter::from_fn(|| select_request(&mut pending))
+            .map(|request| request.request_id)
+            .collect();
+        assert_eq!(vec![Some("high".to_string()), Some("other-high".to_string()), Some("low".to_string())], selected);
+    }
+
+    #[test]
+    fn should_drop_cancelled_requests() {
+
+        let (sender, receiver) = mpsc::channel::<ScanRequest>();
+        let (reply_sender, reply_receiver) = mpsc::channel::<RunnerReply>();
+        let cancel_signal = CancelSignal::default();
+        let mut control_channel = ControlChannel {
+            requests: receiver,
+            replies: reply_sender,
+            pending: vec![],
+            cancel_signal: cancel_signal.clone()
+        };
+
+        for request_id in ["req-1", "req-2"] {
+            cancel_signal.queue(request_id);
+            sender.send(ScanRequest { request_id: Some(request_id.into()), ..Default::default() }).unwrap();
+        }
+        assert!(cancel_signal.cancel_request("req-1"));
+
+        assert_eq!(Some("req-2".to_string()), control_channel.next_request().request_id);
+        assert_eq!(vec![RunnerReply::new("req-1", RunnerReplyType::Cancelled, None)], reply_receiver.try_iter().collect::<Vec<RunnerReply>>());
+        assert!(!cancel_signal.cancel_request("req-1"));
+    }
+
 }
@@ -0,0 +1,173 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use anyhow::{Error, Result};
+use flate2::{write::GzEncoder, Compression};
+use glob::{MatchOptions, Pattern};
+
+use crate::utils::open_no_follow;
+
+const BYTES_PER_MB: u64 = 1_000_000;
+
+/// Result file kept after the scan, the content is gzip compressed
+pub struct Artifact {
+
+    pub path: String,
+
+    pub content_type: &'static str,
+
+    pub size: u64,
+
+    pub compressed_content: Vec<u8>
+
+}
+
+/// Collect the result files matching the artifact patterns of a function,
+/// skipped files are described in the returned messages
+pub fn collect_artifacts(result_path: &Path, patterns: &[String], file_limit_mb: u64, total_limit_mb: u64) -> Result<(Vec<Artifact>, Vec<String>), Error> {
+
+    let mut artifacts: Vec<Artifact> = vec![];
+    let mut messages: Vec<String> = vec![];
+
+    if patterns.is_empty() {
+        return Ok((artifacts, messages));
+    }
+
+    let mut compiled_patterns: Vec<Pattern> = vec![];
+    for raw_pattern in patterns {
+        match Pattern::new(raw_pattern) {
+            Ok(pattern) => compiled_patterns.push(pattern),
+            Err(err) => messages.push(format!("Invalid artifact pattern \"{}\" ({})", raw_pattern, err))
+        }
+    }
+
+    let mut result_files: Vec<(String, u64)> = vec![];
+    list_files(result_path, result_path, &mut result_files)?;
+
+    let match_options = MatchOptions { require_literal_separator: true, ..Default::default() };
+    let mut total_size: u64 = 0;
+
+    for (relative_path, file_size) in result_files {
+
+        if !compiled_patterns.iter().any(|pattern| pattern.matches_with(&relative_path, match_options)) {
+            continue;
+        }
+
+        if file_size > file_limit_mb * BYTES_PER_MB {
+            messages.push(format!("Artifact {} skipped, {} bytes exceed the {}Mb file limit", relative_path, file_size, file_limit_mb));
+            continue;
+        }
+        if total_size + file_size > total_limit_mb * BYTES_PER_MB {
+            messages.push(format!("Artifact {} skipped, the {}Mb scan limit is reached", relative_path, total_limit_mb));
+            continue;
+        }
+        total_size += file_size;
+
+        // The file could have been replaced by a link since the listing
+        let artifact_file = open_no_follow(&result_path.join(&relative_path))?;
+        if !artifact_file.metadata()?.is_file() {
+            messages.push(format!("Artifact {} skipped, only regular files are accepted", relative_path));
+            continue;
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        io::copy(&mut artifact_file.take(file_size), &mut encoder)?;
+
+        artifacts.push(Artifact {
+            content_type: guess_content_type(&relative_path),
+            path: relative_path,
+            size: file_size,
+            compressed_content: encoder.finish()?
+        });
+    }
+
+    Ok((artifacts, messages))
+}
+
+/// List regular files recursively with their path relative to the base directory,
+/// symbolic links are never followed
+fn list_files(base_path: &Path, directory_path: &Path, files: &mut Vec<(String, u64)>) -> Result<(), Error> {
+
+    let mut entries: Vec<fs::DirEntry> = fs::read_dir(directory_path)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+
+        let metadata = fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            list_files(base_path, &entry.path(), files)?;
+        }
+        else if metadata.is_file() {
+            let relative_path = entry.path().strip_prefix(base_path)?.to_string_lossy().to_string();
+            files.push((relative_path, metadata.len()));
+        }
+    }
+
+    Ok(())
+}
+
+fn guess_content_type(file_path: &str) -> &'static str {
+
+    let extension = Path::new(file_path).extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "json" | "sarif" => "application/json",
+        "xml" => "application/xml",
+        "csv" => "text/csv",
+        "txt" | "log" | "md" => "text/plain",
+        "toml" => "application/toml",
+        "pdf" => "application/pdf",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        _ => "application/octet-stream"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::io::Read;
+
+    use anyhow::Error;
+    use flate2::read::GzDecoder;
+    use crate::test_utils::TempDir;
+    use super::{collect_artifacts, guess_content_type};
+
+    #[test]
+    fn should_collect_matching_artifacts() -> Result<(), Error> {
+
+        let result_dir = TempDir::new("artifacts")?;
+        let result_path = result_dir.path();
+        fs::create_dir_all(result_path.join("reports"))?;
+        fs::write(result_path.join("data.toml"), "lines = 1")?;
+        fs::write(result_path.join("reports/index.html"), "<html></html>")?;
+        fs::write(result_path.join("large.json"), "x".repeat(2_000_001))?;
+
+        let patterns = vec!["reports/*.html".to_string(), "*.json".to_string()];
+        let (artifacts, messages) = collect_artifacts(result_path, &patterns, 2, 10)?;
+
+        assert_eq!(artifacts.len(), 1);
+        assert_eq!(artifacts[0].path, "reports/index.html");
+        assert_eq!(artifacts[0].content_type, "text/html");
+        assert_eq!(messages.len(), 1);
+
+        let mut decompressed_content = String::new();
+        GzDecoder::new(artifacts[0].compressed_content.as_slice()).read_to_string(&mut decompressed_content)?;
+        assert_eq!(decompressed_content, "<html></html>");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_guess_content_type() {
+
+        assert_eq!(guess_content_type("report.SARIF"), "application/json");
+        assert_eq!(guess_content_type("sbom"), "application/octet-stream");
+    }
+
+}
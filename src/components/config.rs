@@ -1,7 +1,8 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::{fs, env};
 
-use anyhow::Error;
+use anyhow::{bail, Error};
 use serde::Deserialize;
 
 pub const TOKEN_ENV: &str = "CHICON_TOKEN"; 
@@ -14,10 +15,54 @@ fn get_default_cache_limit() -> String {
     "200".to_string()
 }
 
+fn get_default_artifact_file_limit() -> u64 {
+    10
+}
+
+fn get_default_artifact_total_limit() -> u64 {
+    50
+}
+
+fn get_default_result_file_limit() -> u64 {
+    10
+}
+
+fn get_default_result_total_limit() -> u64 {
+    50
+}
+
+fn get_default_max_issues() -> usize {
+    10_000
+}
+
+fn get_default_max_metrics() -> usize {
+    1_000
+}
+
 fn get_default_namespace() -> String {
     "kb".to_string()
 }
 
+fn get_default_prewarm() -> bool {
+    true
+}
+
+fn get_default_apparmor_profile() -> String {
+    "docker-default".to_string()
+}
+
+fn get_default_egress_network() -> String {
+    "chicon-egress".to_string()
+}
+
+fn get_default_orphan_period() -> u64 {
+    300
+}
+
+fn get_default_stage_timeout() -> u64 {
+    3600
+}
+
 fn get_default_base_url() -> String {
     "localhost:3000".to_string()
 }
@@ -48,7 +93,25 @@ pub struct ConfigWorkspace {
     #[serde(default = "get_default_cache_limit")]
     pub cache_limit: String,
 
-    pub ssh_clone_key: Option<String>
+    pub ssh_clone_key: Option<String>,
+
+    #[serde(default = "get_default_artifact_file_limit")]
+    pub artifact_file_limit: u64,   // Mb, before compression
+
+    #[serde(default = "get_default_artifact_total_limit")]
+    pub artifact_total_limit: u64,  // Mb per scan, before compression
+
+    #[serde(default = "get_default_result_file_limit")]
+    pub result_file_limit: u64,     // Mb
+
+    #[serde(default = "get_default_result_total_limit")]
+    pub result_total_limit: u64,    // Mb per scan
+
+    #[serde(default = "get_default_max_issues")]
+    pub max_issues: usize,
+
+    #[serde(default = "get_default_max_metrics")]
+    pub max_metrics: usize
 
 }
 
@@ -59,7 +122,13 @@ impl Default for ConfigWorkspace {
         ConfigWorkspace {
             path: get_default_path(),
             cache_limit: get_default_cache_limit(),
-            ssh_clone_key: None
+            ssh_clone_key: None,
+            artifact_file_limit: get_default_artifact_file_limit(),
+            artifact_total_limit: get_default_artifact_total_limit(),
+            result_file_limit: get_default_result_file_limit(),
+            result_total_limit: get_default_result_total_limit(),
+            max_issues: get_default_max_issues(),
+            max_metrics: get_default_max_metrics()
         }
     }
 
@@ -81,7 +150,10 @@ pub struct ConfigScheduler {
     pub retry_scale_factor: f32,
 
     #[serde(default = "get_default_retry_limit")]
-    pub retry_scale_limit: u64
+    pub retry_scale_limit: u64,
+
+    #[serde(default)]
+    pub protocol: ProtocolVersion   // Highest scan protocol offered when authenticating
 
 }
 
@@ -95,17 +167,188 @@ impl Default for ConfigScheduler {
             retry_period: get_default_retry_period(),
             retry_scale_factor: get_default_retry_factor(),
             retry_scale_limit: get_default_retry_limit(),
+            protocol: ProtocolVersion::default(),
         }
     }
 
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtocolVersion {
+
+    V1,
+
+    #[default]
+    V2
+
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PullPolicy {
+
+    Always,
+
+    #[default]
+    IfNotPresent,
+
+    Never
+
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ConfigRegistry {
+
+    pub username: String,
+
+    pub password: String
+
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct ConfigRetention {
+
+    pub unused_hours: Option<u64>,  // Images unused for this period are removed
+
+    pub disk_budget: Option<u64>    // Megabytes, least recently used images are removed first
+
+}
+
+impl ConfigRetention {
+
+    pub fn is_enabled(&self) -> bool {
+        self.unused_hours.is_some() || self.disk_budget.is_some()
+    }
+
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessControl {
+
+    #[default]
+    Apparmor,
+
+    Selinux,
+
+    None
+
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskedPathKind {
+
+    File,   // Hidden behind /dev/null
+
+    Directory   // Hidden behind an empty tmpfs
+
+}
+
+/// Container path hidden by an empty read-only mount, the kind must match the
+/// object found in the image or the container creation fails
+#[derive(Deserialize, Clone, PartialEq, Debug)]
+pub struct MaskedPath {
+
+    pub path: String,
+
+    pub kind: MaskedPathKind
+
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ConfigSecurity {
+
+    #[serde(default)]
+    pub access_control: AccessControl,
+
+    #[serde(default = "get_default_apparmor_profile")]
+    pub apparmor_profile: String,
+
+    pub selinux_label: Option<String>,  // Such as "type:container_t"
+
+    pub seccomp_profile: Option<String>,
+
+    pub runtime: Option<String>,    // Alternative OCI runtime (runsc, kata, ...)
+
+    pub default_user: Option<String>,   // Used when the function environment has no user
+
+    #[serde(default)]
+    pub masked_paths: Vec<MaskedPath>
+
+}
+
+impl Default for ConfigSecurity {
+
+    fn default() -> Self {
+
+        ConfigSecurity {
+            access_control: AccessControl::default(),
+            apparmor_profile: get_default_apparmor_profile(),
+            selinux_label: None,
+            seccomp_profile: None,
+            runtime: None,
+            default_user: None,
+            masked_paths: vec![]
+        }
+    }
+
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ConfigEgress {
+
+    #[serde(default = "get_default_egress_network")]
+    pub network: String,
+
+    pub subnet: Option<String>,
+
+    pub proxy_listen: Option<String>,  // Defaults to the proxy address, the bridge gateway
+
+    pub proxy_address: String  // Proxy address as seen from containers
+
+}
+
+#[derive(Deserialize, Clone)]
 pub struct ConfigContainer {
 
     #[serde(default = "get_default_namespace")]
     pub namespace: String,
 
+    pub runner_id: Option<String>,  // Used to label containers launched by this runner, generated in the workspace by default
+
+    #[serde(default = "get_default_orphan_period")]
+    pub orphan_check_period: u64,
+
+    #[serde(default = "get_default_stage_timeout")]
+    pub stage_timeout: u64,     // Seconds before a stage container is killed
+
+    #[serde(default)]
+    pub pull_policy: PullPolicy,
+
+    #[serde(default)]
+    pub registries: HashMap<String, ConfigRegistry>,   // Credentials indexed by registry host
+
+    #[serde(default)]
+    pub mirrors: HashMap<String, String>,   // Registry host rewrites (docker.io -> mirror.local)
+
+    #[serde(default)]
+    pub offline: bool,  // Registries are never contacted in offline mode
+
+    pub image_store: Option<String>,    // Directory with image archives and a manifest.toml
+
+    #[serde(default = "get_default_prewarm")]
+    pub prewarm: bool,
+
+    #[serde(default)]
+    pub retention: ConfigRetention,
+
+    #[serde(default)]
+    pub security: ConfigSecurity,
+
+    pub egress: Option<ConfigEgress>
+
 }
 
 impl Default for ConfigContainer {
@@ -113,7 +356,19 @@ impl Default for ConfigContainer {
     fn default() -> Self {
 
         ConfigContainer {
-            namespace: get_default_namespace()
+            namespace: get_default_namespace(),
+            runner_id: None,
+            orphan_check_period: get_default_orphan_period(),
+            stage_timeout: get_default_stage_timeout(),
+            pull_policy: PullPolicy::default(),
+            registries: HashMap::new(),
+            mirrors: HashMap::new(),
+            offline: false,
+            image_store: None,
+            prewarm: get_default_prewarm(),
+            retention: ConfigRetention::default(),
+            security: ConfigSecurity::default(),
+            egress: None
         }
     }
 
@@ -142,10 +397,26 @@ impl Config {
         let content = fs::read_to_string(path)?;
 
         let config: Config = toml::from_str(&content)?;
+        config.validate()?;
 
         Ok(config)
     }
 
+    /// Reject security settings that would silently weaken the container sandbox
+    pub fn validate(&self) -> Result<(), Error> {
+
+        let security = &self.container.security;
+
+        if security.access_control == AccessControl::Selinux && security.selinux_label.is_none() {
+            bail!("The 'selinux' access control requires a 'selinux_label'");
+        }
+        if let Some(masked_path) = security.masked_paths.iter().find(|masked_path| !masked_path.path.starts_with('/')) {
+            bail!("Masked path '{}' should be absolute", masked_path.path);
+        }
+
+        Ok(())
+    }
+
     pub fn get_cache_bytes(&self) -> Result<u64, Error> {
 
         let cache_mb: u64 = self.workspace.cache_limit.parse()?;
@@ -161,3 +432,38 @@ impl Config {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use super::{Config, MaskedPath, MaskedPathKind};
+
+    #[test]
+    fn should_parse_masked_paths() -> Result<(), Error> {
+
+        let config: Config = toml::from_str(r#"
+            [container.security]
+            masked_paths = [{ path = "/proc/kcore", kind = "file" }, { path = "/sys/firmware", kind = "directory" }]
+        "#)?;
+
+        assert!(config.validate().is_ok());
+        assert_eq!(config.container.security.masked_paths[1], MaskedPath { path: "/sys/firmware".into(), kind: MaskedPathKind::Directory });
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_require_selinux_label() -> Result<(), Error> {
+
+        let config: Config = toml::from_str(r#"
+            [container.security]
+            access_control = "selinux"
+        "#)?;
+
+        assert!(config.validate().is_err());
+
+        Ok(())
+    }
+
+}
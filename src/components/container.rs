@@ -1,153 +1,513 @@
-use std::{process::Command, time::SystemTime, collections::HashMap, rc::Rc};
+use std::{process::{Command, ExitStatus, Stdio}, time::{Duration, SystemTime}, rc::Rc};
+use std::io::Read;
+use std::thread::{self, JoinHandle};
 
-use anyhow::{Context, Error, Result};
-use log::info;
+use anyhow::{bail, Context, Error, Result};
+use log::{info, warn};
 
-use crate::models::{CodeFunction, ScanMetadata, Scan, GitCommit};
+use crate::models::{CacheScope, CodeFunction, FailureReason, ResourceUsage, ScanStage, Scan, GitCommit};
 
-use super::{workspace::Workspace, config::Config};
+use super::{workspace::Workspace, config::{AccessControl, Config, ConfigSecurity, MaskedPath, MaskedPathKind}, image::{pull_image, record_image_usage}, proxy::EgressProxy, tracker::ContainerTracker, usage::UsageSampler};
 
-pub fn pull_image(image_tag: &str, namespace_arg: &str) -> Result<(), Error> {
+const POLL_PERIOD: Duration = Duration::from_millis(250);
+const DEFAULT_FILE_MODE: &str = "0644";
 
-    let process_result = Command::new("nerdctl")
-        .arg(namespace_arg)
-        .arg("image")
-        .arg("inspect")
-        .arg(image_tag)
-        .output();
+struct StageOutput {
 
-    let has_image = match process_result {
-        Ok(output) => output.status.success(),
-        Err(_) => false
-    };
+    status: ExitStatus,
 
-    if has_image {
-        return Ok(());
-    }
+    stdout: Vec<u8>,
+
+    stderr: Vec<u8>,
+
+    is_cancelled: bool,
+
+    is_timed_out: bool,
+
+    oom_killed: bool,
+
+    usage: ResourceUsage
 
-    info!("Pulling container image {}", image_tag);
-    Command::new("nerdctl")
-        .arg(namespace_arg)
-        .arg("image")
-        .arg("pull")
-        .arg(image_tag)
-        .output()?;
-    Ok(())
 }
 
-pub fn run_container(config: Rc<Config>, workspace: &Workspace, repository_id: &str, code_function: &CodeFunction, commit: GitCommit) -> Result<Scan, Error> {
+/// Keep the first failure of a function run, following stages may fail as a consequence
+fn record_failure(failure: &mut Option<(FailureReason, String)>, failure_reason: FailureReason, error_message: String) {
+
+    if failure.is_none() {
+        *failure = Some((failure_reason, error_message));
+    }
+}
+
+pub fn run_container(config: Rc<Config>, workspace: &Workspace, tracker: &ContainerTracker, egress_proxy: Option<&EgressProxy>, repository_id: &str, code_function: &CodeFunction, commit: GitCommit) -> Result<Scan, Error> {
 
     workspace.clean(repository_id, false).context("Could not clean workspace before run")?;
+    let active_scan = tracker.start_scan();
 
     let mut timing_ms: usize = 0;
     let mut logs = "".to_string();
-    let mut has_failed = false;
+    let mut failure: Option<(FailureReason, String)> = None;
+    let mut is_cancelled = false;
+    let mut stages: Vec<ScanStage> = vec![];
+    let mut function_usage = ResourceUsage::default();
+    let timeout_seconds = code_function.stage_timeout.unwrap_or(config.container.stage_timeout);
+
+    // Stages of a function share the same writable copy, removed on any exit
+    let scratch = match code_function.capabilities.writable_workspace {
+        true => Some(workspace.create_scratch(repository_id).context("Could not create writable workspace")?),
+        false => None
+    };
+    let repository_mount = match &scratch {
+        Some(scratch) => format!("{}:/workspace", scratch.path().display()),
+        None => format!("{}/{}/repository:/workspace:ro", config.workspace.path, repository_id)
+    };
+
+    // Container users other than root map to host IDs unknown to the runner (subordinate
+    // ranges in rootless mode), caches are then made writable by any user
+    let default_user = config.container.security.default_user.as_ref();
+    let is_shared_cache = code_function.stages.iter()
+        .any(|stage| !is_root_user(stage.environment.user.as_ref().or(default_user)));
+
+    let mut cache_mounts: Vec<String> = vec![];
+    for function_cache in &code_function.caches {
+
+        if !function_cache.path.starts_with('/') {
+            bail!("Cache mount path '{}' should be absolute", function_cache.path);
+        }
+
+        let cache_repository = match function_cache.scope {
+            CacheScope::Function => None,
+            CacheScope::Repository => Some(repository_id)
+        };
+        let cache_path = workspace.get_cache_path(&code_function.public_id, cache_repository, &function_cache.name, is_shared_cache)
+            .with_context(|| format!("Could not prepare cache {}", function_cache.name))?;
+        cache_mounts.push(format!("{}:{}", cache_path.display(), function_cache.path));
+    }
 
     let stage_total = code_function.stages.len();
     let mut stage_count = 0;
 
-    for stage in &code_function.stages {
+    // Runner errors during the stages fail the scan without losing the logs and stages collected so far
+    let mut run_stages = || -> Result<(), Error> {
 
-        stage_count += 1;
-        info!("Executing stage of {}/{} \"{}\" : environment {} ({})", stage_count, stage_total, code_function.name, stage.environment.name, stage.environment.base_image);
+        for stage in &code_function.stages {
 
-        let script_path = format!("bin/process.{}", &stage.environment.file_extension);
-        workspace.write_string(repository_id, &script_path, &stage.content)?;
+            if tracker.cancel_signal().is_requested(repository_id) {
+                is_cancelled = true;
+                break;
+            }
 
-        let namespace_arg = format!("--namespace={}", config.container.namespace);
-        pull_image(&stage.environment.base_image, &namespace_arg).context("Could not pull container image")?;
+            stage_count += 1;
+            info!("Executing stage of {}/{} \"{}\" : environment {} ({})", stage_count, stage_total, code_function.name, stage.environment.name, stage.environment.base_image);
 
-        let mut nerdctl = Command::new("nerdctl");
-        nerdctl
-            .arg(&namespace_arg)
-            .arg("run")
-            .arg("--rm")
-            // Security flags
-            .arg("--cap-drop")
-            .arg("all")
-            .arg("--security-opt")
-            .arg("apparmor=docker-default")
-            .arg("--security-opt")
-            .arg("no-new-privileges");
+            if stage.entrypoint.is_none() || !stage.content.is_empty() {
+                let script_path = format!("bin/process.{}", &stage.environment.file_extension);
+                workspace.write_string(repository_id, &script_path, &stage.content)?;
+            }
 
-        if code_function.capabilities.network {
-            nerdctl.arg("--network").arg("bridge");
-        }
-        else {
-            nerdctl.arg("--network").arg("none");
-        }
+            for stage_file in &stage.files {
+
+                let raw_mode = stage_file.mode.as_deref().unwrap_or(DEFAULT_FILE_MODE);
+                let mode = u32::from_str_radix(raw_mode, 8)
+                    .with_context(|| format!("Invalid file mode '{}' for stage file {}", raw_mode, stage_file.path))?;
+                workspace.write_bin_file(repository_id, &stage_file.path, &stage_file.content, mode & 0o777)?;
+            }
+
+            let namespace_arg = format!("--namespace={}", config.container.namespace);
+            let pull_policy = code_function.pull_policy.unwrap_or(config.container.pull_policy);
+            let image_tag = match pull_image(&config.container, &stage.environment.base_image, pull_policy) {
+                Ok(image_tag) => image_tag,
+                Err(err) => {
+                    warn!("Could not pull container image {} ({:#})", stage.environment.base_image, err);
+                    record_failure(&mut failure, FailureReason::ImagePullError, format!("{:#}", err));
+                    break;
+                }
+            };
+            record_image_usage(workspace.get_path(), &image_tag).unwrap_or_else(|err| {
+                warn!("Could not record image usage ({:#})", err);
+            });
+
+            let mut nerdctl = Command::new("nerdctl");
+            nerdctl
+                .arg(&namespace_arg)
+                .arg("run")
+                .args(tracker.container_args(&active_scan.key, &code_function.public_id, stage_count))
+                .args(security_args(&config.container.security));
+
+            let stage_proxy = if code_function.capabilities.egress.is_empty() {
+                None
+            }
+            else {
+                let proxy = egress_proxy.context("Function declares an egress allowlist but no egress proxy is configured")?;
+                proxy.allow(&code_function.capabilities.egress).context("Invalid egress allowlist")?;
+                Some(proxy)
+            };
+
+            if let Some(proxy) = stage_proxy {
+                nerdctl.args(proxy.container_args());
+            }
+            else if code_function.capabilities.network {
+                nerdctl.arg("--network").arg("bridge");
+            }
+            else {
+                nerdctl.arg("--network").arg("none");
+            }
         
-        if let Some(user) = &stage.environment.user {
-            nerdctl.arg("--user").arg(user);
-        }
+            let potential_user = stage.environment.user.as_ref().or(config.container.security.default_user.as_ref());
+            if let Some(user) = potential_user {
+                nerdctl.arg("--user").arg(user);
+            }
 
-        nerdctl.arg("--volume") // Volume mounting
-            .arg(format!("{}/{}/repository:/workspace:ro", config.workspace.path, repository_id))
-            .arg("--volume")
-            .arg(format!("{}/{}/bin:/tmp-bin:ro", config.workspace.path, repository_id))
-            .arg("--volume")
-            .arg(format!("{}/{}/result:/result", config.workspace.path, repository_id))
-            .arg("--workdir")
-            .arg("/workspace");
-
-        if !code_function.capabilities.filesystem {
-            nerdctl.arg("--read-only");
-        }
+            nerdctl.arg("--volume") // Volume mounting
+                .arg(&repository_mount)
+                .arg("--volume")
+                .arg(format!("{}/{}/bin:/tmp-bin:ro", config.workspace.path, repository_id))
+                .arg("--volume")
+                .arg(format!("{}/{}/result:/result", config.workspace.path, repository_id))
+                .arg("--workdir")
+                .arg("/workspace");
+
+            for cache_mount in &cache_mounts {
+                nerdctl.arg("--volume").arg(cache_mount);
+            }
+
+            if !code_function.capabilities.filesystem {
+                nerdctl.arg("--read-only");
+            }
         
-        // Binary
-        nerdctl.arg(&stage.environment.base_image)
-            .arg(&stage.environment.executor)
-            .arg(format!("/tmp-bin/process.{}", &stage.environment.file_extension));
+            // Binary (the image entrypoint is replaced when overridden by the stage)
+            match stage.entrypoint.as_deref() {
+                Some([entrypoint, entrypoint_args @ ..]) => {
+                    nerdctl.arg("--entrypoint")
+                        .arg(entrypoint)
+                        .arg(&image_tag)
+                        .args(entrypoint_args);
+                },
+                _ => {
+                    nerdctl.arg(&image_tag)
+                        .arg(&stage.environment.executor)
+                        .arg(format!("/tmp-bin/process.{}", &stage.environment.file_extension));
+                }
+            }
+            nerdctl.args(&stage.arguments);
         
-        let start_time = SystemTime::now();
-        let output = nerdctl.output()?;
-        timing_ms += crate::utils::compute_time_diff(start_time)?;
-
-        let stderr_logs = String::from_utf8(output.stderr).unwrap_or_else(|_| "(invalid UTF8 string)".to_string());
-        let stdout_logs = String::from_utf8(output.stdout).unwrap_or_else(|_| "(invalid UTF8 string)".to_string());
-        logs.push_str(
-            &format!("{}\n{}", stdout_logs, stderr_logs) // TODO More accurate mix
-        );
-
-        if !output.status.success() {
-            has_failed = true;
-        }
+            let container_name = tracker.container_name(&active_scan.key, &code_function.public_id, stage_count);
 
-        workspace.clean_bin(repository_id)?;
-    }
+            let start_time = SystemTime::now();
+            let stage_timeout = Duration::from_secs(timeout_seconds);
+            let output = execute_stage(nerdctl, tracker, &container_name, repository_id, stage_timeout)?;
+            timing_ms += crate::utils::compute_time_diff(start_time)?;
+
+            let stderr_logs = String::from_utf8(output.stderr).unwrap_or_else(|_| "(invalid UTF8 string)".to_string());
+            let stdout_logs = String::from_utf8(output.stdout).unwrap_or_else(|_| "(invalid UTF8 string)".to_string());
+            logs.push_str(
+                &format!("{}\n{}", stdout_logs, stderr_logs) // TODO More accurate mix
+            );
+
+            if let Some(proxy) = stage_proxy {
+                let egress_report = proxy.reset();
+                for denied_connection in egress_report.denied {
+                    warn!("Egress connection denied for function \"{}\": {}", code_function.name, denied_connection);
+                    logs.push_str(&format!("[chicon] Egress connection denied: {}\n", denied_connection));
+                }
+                for failed_connection in egress_report.failed {
+                    logs.push_str(&format!("[chicon] Egress upstream error: {}\n", failed_connection));
+                }
+            }
 
-    let mut metric_results: Option<HashMap<String, crate::models::MetricValue>> = None;
-    let potential_toml = workspace.read_string(repository_id, "result/data.toml");
+            function_usage.add(&output.usage);
+            stages.push(ScanStage {
+                index: stage_count,
+                environment: stage.environment.name.to_string(),
+                exit_code: output.status.code(),
+                usage: output.usage
+            });
 
-    if let Ok(toml_content) = potential_toml {
+            if output.is_timed_out {
+                let error_message = format!("Stage {} exceeded the {}s timeout", stage_count, timeout_seconds);
+                record_failure(&mut failure, FailureReason::Timeout, error_message);
+            }
+            else if output.oom_killed {
+                let error_message = format!("Stage {} was killed after running out of memory", stage_count);
+                record_failure(&mut failure, FailureReason::OomKilled, error_message);
+            }
+            else if !output.status.success() && !output.is_cancelled {
+                let error_message = format!("Stage {} exited with {}", stage_count, output.status);
+                record_failure(&mut failure, FailureReason::NonZeroExit, error_message);
+            }
 
-        if let Ok(toml_results) = toml::from_str(&toml_content) {    // TODO TOML error handling
-            metric_results = Some(toml_results);
+            workspace.clean_bin(repository_id)?;
+
+            if output.is_cancelled {
+                is_cancelled = true;
+                break;
+            }
+            if output.is_timed_out {
+                break;
+            }
         }
-    }
 
-    let results: Vec<ScanMetadata> = metric_results.unwrap_or_default().into_iter().map(|(result_key, result_value)| {
-        
-        let potential_description = code_function.outputs.iter()
-            .find(|output| output.key == result_key)
-            .map(|output| output.description.clone());
-
-        ScanMetadata {
-            key: result_key,
-            description: potential_description.unwrap_or_default(),
-            value: result_value
+        Ok(())
+    };
+    if let Err(err) = run_stages() {
+        warn!("Function \"{}\" interrupted by a runner error ({:#})", code_function.name, err);
+        logs.push_str(&format!("[chicon] Runner error: {:#}\n", err));
+        record_failure(&mut failure, FailureReason::RunnerInternalError, format!("{:#}", err));
+
+        // The stage may have failed before its egress allowlist was reset
+        if let Some(proxy) = egress_proxy {
+            proxy.reset();
         }
+    }
+
+    let usage_total = tracker.record_usage(&code_function.public_id, &function_usage);
+    info!(
+        "Function \"{}\" runner-wide usage: {}ms wall time, {}ms CPU time, {} bytes peak memory, {} bytes read, {} bytes written",
+        code_function.name, usage_total.wall_time_ms, usage_total.cpu_time_ms.unwrap_or_default(), usage_total.peak_memory_bytes.unwrap_or_default(),
+        usage_total.read_bytes.unwrap_or_default(), usage_total.written_bytes.unwrap_or_default()
+    );
 
-    }).collect();
+    if is_cancelled {
+        info!("Function \"{}\" cancelled by the scheduler", code_function.name);
+        logs.push_str("[chicon] Scan cancelled by the scheduler\n");
+    }
+
+    if let Some(scratch) = scratch {
+        scratch.remove().unwrap_or_else(|err| {
+            warn!("Could not remove writable workspace ({:#})", err);
+        });
+    }
+
+    let has_failed = failure.is_some() || is_cancelled;
+    let (failure_reason, error_message) = failure.unzip();
 
     let finished_scan = Scan {
         function_id: code_function.public_id.to_string(),
         repository_id: repository_id.to_string(),
-        commit,
+        commit: Some(commit),
         has_failed,
+        failure_reason,
+        error_message,
+        is_cancelled,
+        is_truncated: false,
         logs,
         timing_ms,
-        results
+        results: vec![],    // Results are read once the function has run
+        stages,
+        gate_violations: vec![]
     };
     Ok(finished_scan)
-}
\ No newline at end of file
+}
+
+/// Launch a stage container and wait for its completion, the container is
+/// killed when the scheduler cancels the scan or when the timeout is reached
+fn execute_stage(mut nerdctl: Command, tracker: &ContainerTracker, container_name: &str, repository_id: &str, timeout: Duration) -> Result<StageOutput, Error> {
+
+    let start_time = SystemTime::now();
+
+    let mut child = nerdctl
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Pipes are drained in threads to avoid blocking the container
+    let stdout_reader = spawn_pipe_reader(child.stdout.take());
+    let stderr_reader = spawn_pipe_reader(child.stderr.take());
+
+    let mut is_cancelled = false;
+    let mut is_timed_out = false;
+    let mut is_killed = false;
+    let mut has_kill_failed = false;
+    let mut sampler = UsageSampler::default();
+
+    let status = loop {
+
+        // Sampling before the exit check gives short stages a chance to be measured
+        if !sampler.is_attached() {
+            if let Some(pid) = tracker.inspect_pid(container_name) {
+                sampler.attach(pid);
+            }
+        }
+        if sampler.needs_stats() {
+            sampler.record_stats(tracker.container_stats(container_name).as_deref());
+        }
+        sampler.sample();
+
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if !is_cancelled && tracker.cancel_signal().is_requested(repository_id) {
+            info!("Container {} cancelled, killing container", container_name);
+            is_cancelled = true;
+        }
+
+        let elapsed = start_time.elapsed().unwrap_or_default();
+        if !is_timed_out && elapsed > timeout {
+            warn!("Container {} reached the stage timeout, killing container", container_name);
+            is_timed_out = true;
+        }
+
+        // The container may not exist yet when the kill is first requested, it is retried on each poll
+        if (is_cancelled || is_timed_out) && !is_killed {
+            match tracker.kill_container(container_name) {
+                Ok(()) => is_killed = true,
+                Err(err) => {
+                    if !has_kill_failed {
+                        warn!("Could not kill container, retrying until it exits ({})", err);
+                    }
+                    has_kill_failed = true;
+                }
+            }
+        }
+
+        thread::sleep(POLL_PERIOD);
+    };
+
+    let wall_time_ms = start_time.elapsed().unwrap_or_default().as_millis() as u64;
+
+    // Counters are read a last time before removal, the cgroup may outlive the container process
+    sampler.sample();
+
+    // The container is kept after exit for the state inspection
+    let state = tracker.inspect_state(container_name).unwrap_or_else(|err| {
+        warn!("Could not inspect container state ({})", err);
+        Default::default()
+    });
+    tracker.remove_container(container_name).unwrap_or_else(|err| {
+        warn!("Could not remove stage container ({})", err);
+    });
+
+    let stage_output = StageOutput {
+        status,
+        stdout: stdout_reader.join().unwrap_or_default(),
+        stderr: stderr_reader.join().unwrap_or_default(),
+        is_cancelled,
+        is_timed_out,
+        oom_killed: state.oom_killed,
+        usage: sampler.finish(wall_time_ms)
+    };
+    Ok(stage_output)
+}
+
+fn spawn_pipe_reader<R: Read + Send + 'static>(potential_pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+
+    thread::spawn(move || {
+
+        let mut content: Vec<u8> = vec![];
+        if let Some(mut pipe) = potential_pipe {
+            pipe.read_to_end(&mut content).ok();
+        }
+        content
+    })
+}
+
+/// Build the security flags for a container based on the runner policy
+fn security_args(security: &ConfigSecurity) -> Vec<String> {
+
+    let mut args: Vec<String> = vec![
+        "--cap-drop".into(),
+        "all".into(),
+        "--security-opt".into(),
+        "no-new-privileges".into()
+    ];
+
+    match security.access_control {
+        AccessControl::Apparmor => {
+            args.push("--security-opt".into());
+            args.push(format!("apparmor={}", security.apparmor_profile));
+        },
+        AccessControl::Selinux => {
+            if let Some(selinux_label) = &security.selinux_label {
+                args.push("--security-opt".into());
+                args.push(format!("label={}", selinux_label));
+            }
+        },
+        AccessControl::None => {}
+    }
+
+    if let Some(seccomp_profile) = &security.seccomp_profile {
+        args.push("--security-opt".into());
+        args.push(format!("seccomp={}", seccomp_profile));
+    }
+
+    if let Some(runtime) = &security.runtime {
+        args.push("--runtime".into());
+        args.push(runtime.to_string());
+    }
+
+    for masked_path in &security.masked_paths {
+        args.extend(mask_args(masked_path));
+    }
+
+    args
+}
+
+/// Only an explicit root user is known to run as root, images may define another default user
+fn is_root_user(potential_user: Option<&String>) -> bool {
+
+    potential_user
+        .and_then(|user| user.split(':').next())
+        .is_some_and(|user| user == "0" || user == "root")
+}
+
+/// Directories are hidden behind an empty read-only tmpfs, files behind /dev/null
+fn mask_args(masked_path: &MaskedPath) -> [String; 2] {
+
+    match masked_path.kind {
+        MaskedPathKind::Directory => ["--tmpfs".into(), format!("{}:ro,size=4k", masked_path.path)],
+        MaskedPathKind::File => ["--volume".into(), format!("/dev/null:{}:ro", masked_path.path)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::components::config::{AccessControl, ConfigSecurity, MaskedPath, MaskedPathKind};
+    use super::{is_root_user, security_args};
+
+    #[test]
+    fn should_build_security_args() {
+
+        let security = ConfigSecurity {
+            access_control: AccessControl::Selinux,
+            selinux_label: Some("type:container_t".into()),
+            seccomp_profile: Some("/etc/chicon/seccomp.json".into()),
+            runtime: Some("runsc".into()),
+            ..Default::default()
+        };
+
+        let expected_args = vec![
+            "--cap-drop", "all", "--security-opt", "no-new-privileges", "--security-opt", "label=type:container_t",
+            "--security-opt", "seccomp=/etc/chicon/seccomp.json", "--runtime", "runsc"
+        ];
+        assert_eq!(security_args(&security), expected_args);
+        assert!(security_args(&ConfigSecurity::default()).contains(&"apparmor=docker-default".to_string()));
+    }
+
+    #[test]
+    fn should_mask_files_and_directories() {
+
+        let security = ConfigSecurity {
+            access_control: AccessControl::None,
+            masked_paths: vec![
+                MaskedPath { path: "/sys/firmware".into(), kind: MaskedPathKind::Directory },
+                MaskedPath { path: "/proc/kcore".into(), kind: MaskedPathKind::File }
+            ],
+            ..Default::default()
+        };
+
+        let args = security_args(&security);
+        assert_eq!(args[4..], ["--tmpfs", "/sys/firmware:ro,size=4k", "--volume", "/dev/null:/proc/kcore:ro"]);
+    }
+
+    #[test]
+    fn should_detect_root_user() {
+
+        assert!(is_root_user(Some(&"0:0".to_string())));
+        assert!(is_root_user(Some(&"root".to_string())));
+        assert!(!is_root_user(Some(&"65534:65534".to_string())));
+        assert!(!is_root_user(None));
+    }
+
+}
@@ -0,0 +1,195 @@
+use anyhow::{bail, Error, Result};
+use log::warn;
+use serde::Deserialize;
+
+use crate::models::{CodeFunction, CodeIssue, FailureReason, GateViolation, MetricValue, QualityGate, Scan, ScanMetadata};
+
+#[derive(Deserialize)]
+struct GateFile {
+
+    #[serde(default)]
+    gates: Vec<QualityGate>
+
+}
+
+/// Parse the quality gates declared in the repository configuration file
+pub fn parse_repository_gates(content: &str) -> Result<Vec<QualityGate>, Error> {
+
+    let gate_file: GateFile = toml::from_str(content)?;
+    Ok(gate_file.gates)
+}
+
+/// Gates applying to a function: its own gates and the repository gates
+/// without scope or scoped to the function
+pub fn select_gates<'a>(code_function: &'a CodeFunction, repository_gates: &'a [QualityGate]) -> Vec<&'a QualityGate> {
+
+    let scoped_gates = repository_gates.iter()
+        .filter(|gate| gate.function.as_ref().map_or(true, |function| *function == code_function.public_id || *function == code_function.name));
+
+    code_function.gates.iter().chain(scoped_gates).collect()
+}
+
+/// Evaluate gates on the issues (suppressed issues are not counted) and metrics of a scan
+pub fn evaluate_gates(gates: &[&QualityGate], issues: &[CodeIssue], metrics: &[ScanMetadata]) -> Vec<GateViolation> {
+
+    let mut violations: Vec<GateViolation> = vec![];
+
+    for gate in gates {
+
+        match evaluate_gate(gate, issues, metrics) {
+            Ok(potential_violation) => violations.extend(potential_violation),
+            Err(err) => warn!("Skipping invalid quality gate {:?} ({})", gate, err)
+        }
+    }
+
+    violations
+}
+
+fn evaluate_gate(gate: &QualityGate, issues: &[CodeIssue], metrics: &[ScanMetadata]) -> Result<Option<GateViolation>, Error> {
+
+    match (&gate.severity, &gate.metric) {
+        (Some(severity), None) => {
+
+            // Without a maximum, any issue of the severity fails the gate
+            let maximum = gate.maximum.unwrap_or(0.0);
+            let issue_count = issues.iter()
+                .filter(|issue| !issue.is_suppressed)
+                .filter(|issue| issue.severity.as_ref().is_some_and(|issue_severity| issue_severity.eq_ignore_ascii_case(severity)))
+                .count() as f64;
+
+            if issue_count <= maximum {
+                return Ok(None);
+            }
+
+            Ok(Some(GateViolation {
+                gate: format!("severity:{}", severity),
+                actual: Some(issue_count),
+                threshold: maximum,
+                message: format!("{} {} issues found, at most {} allowed", issue_count, severity, maximum)
+            }))
+        },
+        (None, Some(metric_key)) => {
+
+            if gate.minimum.is_none() && gate.maximum.is_none() {
+                bail!("a metric gate requires a minimum or a maximum");
+            }
+
+            let potential_value = metrics.iter()
+                .find(|metric| metric.key == *metric_key)
+                .and_then(|metric| match metric.value {
+                    MetricValue::Number(number) => Some(number as f64),
+                    MetricValue::Float(float) => Some(float),
+                    _ => None
+                });
+            let gate_name = format!("metric:{}", metric_key);
+
+            let value = match potential_value {
+                Some(value) => value,
+                None => {
+                    let threshold = gate.minimum.or(gate.maximum).unwrap_or_default();
+                    return Ok(Some(GateViolation {
+                        gate: gate_name,
+                        actual: None,
+                        threshold,
+                        message: format!("Numeric metric {} not found", metric_key)
+                    }));
+                }
+            };
+
+            if let Some(minimum) = gate.minimum.filter(|minimum| value < *minimum) {
+                return Ok(Some(GateViolation {
+                    gate: gate_name,
+                    actual: Some(value),
+                    threshold: minimum,
+                    message: format!("Metric {} is {}, below the {} minimum", metric_key, value, minimum)
+                }));
+            }
+            if let Some(maximum) = gate.maximum.filter(|maximum| value > *maximum) {
+                return Ok(Some(GateViolation {
+                    gate: gate_name,
+                    actual: Some(value),
+                    threshold: maximum,
+                    message: format!("Metric {} is {}, above the {} maximum", metric_key, value, maximum)
+                }));
+            }
+
+            Ok(None)
+        },
+        _ => bail!("a gate requires either a severity or a metric")
+    }
+}
+
+/// List the violations in the scan, the scan fails unless it already failed for another reason
+pub fn report_violations(scan: &mut Scan, violations: Vec<GateViolation>) {
+
+    if violations.is_empty() {
+        return;
+    }
+
+    for violation in violations.iter() {
+        scan.logs.push_str(&format!("[chicon] Quality gate {} failed: {}\n", violation.gate, violation.message));
+    }
+
+    if scan.failure_reason.is_none() && !scan.is_cancelled {
+        scan.has_failed = true;
+        scan.failure_reason = Some(FailureReason::QualityGateFailed);
+        scan.error_message = Some(format!("{} quality gates failed", violations.len()));
+    }
+    scan.gate_violations = violations;
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::models::{CodeIssue, MetricValue, QualityGate, ScanMetadata};
+    use crate::test_utils::build_issue;
+    use super::{evaluate_gates, parse_repository_gates};
+
+    #[test]
+    fn should_evaluate_issue_gates() {
+
+        let any_critical = QualityGate { severity: Some("critical".into()), ..Default::default() };
+        let few_high = QualityGate { severity: Some("high".into()), maximum: Some(1.0), ..Default::default() };
+        let issues = vec![
+            CodeIssue { severity: Some("CRITICAL".into()), is_suppressed: true, ..build_issue("Issue", "a1") },
+            CodeIssue { severity: Some("high".into()), ..build_issue("Issue", "b2") },
+            CodeIssue { severity: Some("high".into()), ..build_issue("Issue", "c3") }
+        ];
+
+        let violations = evaluate_gates(&[&any_critical, &few_high], &issues, &[]);
+
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].gate, "severity:high");
+        assert_eq!(violations[0].actual, Some(2.0));
+        assert_eq!(violations[0].threshold, 1.0);
+    }
+
+    #[test]
+    fn should_evaluate_metric_gates() -> Result<(), anyhow::Error> {
+
+        let gates = parse_repository_gates(r#"
+            [[gates]]
+            metric = "coverage"
+            minimum = 80.0
+
+            [[gates]]
+            metric = "duplication"
+            maximum = 5
+        "#)?;
+        let metrics = vec![ScanMetadata {
+            key: "coverage".into(),
+            description: "".into(),
+            value: MetricValue::Float(72.5),
+            unit: None
+        }];
+
+        let violations = evaluate_gates(&gates.iter().collect::<Vec<&QualityGate>>(), &[], &metrics);
+
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].message, "Metric coverage is 72.5, below the 80 minimum");
+        assert_eq!(violations[1].actual, None);
+
+        Ok(())
+    }
+
+}
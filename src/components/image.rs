@@ -0,0 +1,710 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+use anyhow::{bail, Context, Error, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::models::CodeFunction;
+use crate::utils::current_timestamp;
+use super::config::{ConfigContainer, PullPolicy};
+
+const DEFAULT_REGISTRY: &str = "docker.io";
+const STORE_MANIFEST: &str = "manifest.toml";
+const USAGE_INDEX: &str = "image-usage.toml";
+
+#[derive(Deserialize)]
+struct StoreManifest {
+
+    #[serde(default)]
+    images: Vec<ArchivedImage>
+
+}
+
+/// Image archive (OCI or Docker tar) declared in the local image store
+#[derive(Deserialize)]
+struct ArchivedImage {
+
+    image: String,
+
+    archive: String,
+
+    digest: String  // Expected SHA-256 of the archive file
+
+}
+
+/// Last usage timestamps of images, stored in the workspace
+#[derive(Deserialize, Serialize, Default)]
+struct ImageUsage {
+
+    #[serde(default)]
+    images: HashMap<String, u64>
+
+}
+
+impl ImageUsage {
+
+    fn load(workspace_path: &str) -> ImageUsage {
+
+        fs::read_to_string(Path::new(workspace_path).join(USAGE_INDEX))
+            .ok()
+            .and_then(|content| toml::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, workspace_path: &str) -> Result<(), Error> {
+
+        let content = toml::to_string(self)?;
+        fs::write(Path::new(workspace_path).join(USAGE_INDEX), content)?;
+
+        Ok(())
+    }
+
+}
+
+/// Image listed by nerdctl in the containerd namespace
+#[derive(Deserialize)]
+struct ListedImage {
+
+    #[serde(rename = "Repository")]
+    repository: String,
+
+    #[serde(rename = "Tag")]
+    tag: String,
+
+    #[serde(rename = "Size", default)]
+    size: String
+
+}
+
+/// Container image reference split between the registry host and the remote path
+#[derive(PartialEq, Debug)]
+pub struct ImageReference {
+
+    pub registry: String,
+
+    pub remote: String
+
+}
+
+impl ImageReference {
+
+    /// Parse an image tag with the Docker normalization rules (registry is
+    /// only detected when the first component looks like a host)
+    pub fn parse(image_tag: &str) -> ImageReference {
+
+        if let Some((first_component, remote)) = image_tag.split_once('/') {
+
+            let is_host = first_component.contains('.') || first_component.contains(':') || first_component == "localhost";
+            if is_host {
+                return ImageReference {
+                    registry: first_component.to_string(),
+                    remote: remote.to_string()
+                };
+            }
+
+            return ImageReference {
+                registry: DEFAULT_REGISTRY.to_string(),
+                remote: image_tag.to_string()
+            };
+        }
+
+        // Official images are stored under the library namespace
+        ImageReference {
+            registry: DEFAULT_REGISTRY.to_string(),
+            remote: format!("library/{}", image_tag)
+        }
+    }
+
+    /// Normalized name used to match images, such as "docker.io/library/alpine:latest"
+    pub fn key(&self) -> String {
+
+        let last_component = self.remote.rsplit('/').next().unwrap_or_default();
+        if last_component.contains(':') || last_component.contains('@') {
+            return format!("{}/{}", self.registry, self.remote);
+        }
+        format!("{}/{}:latest", self.registry, self.remote)
+    }
+
+}
+
+/// Rewrite an image tag based on the configured registry mirrors
+pub fn resolve_image(config: &ConfigContainer, image_tag: &str) -> String {
+
+    let reference = ImageReference::parse(image_tag);
+
+    match config.mirrors.get(&reference.registry) {
+        Some(mirror) => format!("{}/{}", mirror.trim_end_matches('/'), reference.remote),
+        None => image_tag.to_string()
+    }
+}
+
+pub fn has_image(image_tag: &str, namespace_arg: &str) -> bool {
+
+    let process_result = Command::new("nerdctl")
+        .arg(namespace_arg)
+        .arg("image")
+        .arg("inspect")
+        .arg(image_tag)
+        .output();
+
+    match process_result {
+        Ok(output) => output.status.success(),
+        Err(_) => false
+    }
+}
+
+/// Ensure that an image is available based on the pull policy, the resolved
+/// image tag (after mirror rewrite) is returned for container usage
+pub fn pull_image(config: &ConfigContainer, image_tag: &str, pull_policy: PullPolicy) -> Result<String, Error> {
+
+    let namespace_arg = format!("--namespace={}", config.namespace);
+
+    // Pull policies do not apply offline, images are only taken from the namespace or the image store
+    if config.offline {
+
+        if !has_image(image_tag, &namespace_arg) && !load_archive(config, &namespace_arg, image_tag)? {
+            bail!("Image {} is not present and no archive is declared in the image store (offline mode)", image_tag);
+        }
+        return Ok(image_tag.to_string());
+    }
+
+    let resolved_tag = resolve_image(config, image_tag);
+
+    match pull_policy {
+        PullPolicy::Never | PullPolicy::IfNotPresent => {
+
+            if has_image(&resolved_tag, &namespace_arg) {
+                return Ok(resolved_tag);
+            }
+            // The registry remains a fallback for a broken image store, unless pulls are disabled
+            match load_archive(config, &namespace_arg, image_tag) {
+                Ok(true) => return Ok(image_tag.to_string()),
+                Ok(false) => {},
+                Err(err) if pull_policy == PullPolicy::IfNotPresent => {
+                    warn!("Could not load image {} from the image store, pulling it instead ({:#})", image_tag, err);
+                },
+                Err(err) => return Err(err)
+            }
+            if pull_policy == PullPolicy::Never {
+                bail!("Image {} is not present and pull policy is set to 'never'", resolved_tag);
+            }
+        },
+        PullPolicy::Always => {}
+    }
+
+    let registry = ImageReference::parse(&resolved_tag).registry;
+    if let Some(credentials) = config.registries.get(&registry) {
+        login_registry(&namespace_arg, &registry, &credentials.username, &credentials.password)
+            .with_context(|| format!("Could not authenticate on registry {}", registry))?;
+    }
+
+    info!("Pulling container image {}", resolved_tag);
+    let output = Command::new("nerdctl")
+        .arg(&namespace_arg)
+        .arg("image")
+        .arg("pull")
+        .arg(&resolved_tag)
+        .output()?;
+
+    if !output.status.success() {
+        let stderr_logs = String::from_utf8_lossy(&output.stderr);
+        bail!("Image pull failed for {} ({})", resolved_tag, stderr_logs.trim());
+    }
+
+    Ok(resolved_tag)
+}
+
+/// Load an image from the local image store, returns false when the image
+/// is not declared in the store manifest
+fn load_archive(config: &ConfigContainer, namespace_arg: &str, image_tag: &str) -> Result<bool, Error> {
+
+    let store_path = match &config.image_store {
+        Some(store_path) => Path::new(store_path),
+        None => return Ok(false)
+    };
+
+    let archive_path = match find_store_archive(store_path, image_tag)? {
+        Some(archive_path) => archive_path,
+        None => return Ok(false)
+    };
+
+    info!("Loading container image {} from archive {}", image_tag, archive_path.display());
+    let output = Command::new("nerdctl")
+        .arg(namespace_arg)
+        .arg("image")
+        .arg("load")
+        .arg("--input")
+        .arg(&archive_path)
+        .output()?;
+
+    if !output.status.success() {
+        let stderr_logs = String::from_utf8_lossy(&output.stderr);
+        bail!("Image load failed for {} ({})", archive_path.display(), stderr_logs.trim());
+    }
+    if !has_image(image_tag, namespace_arg) {
+        bail!("Archive {} does not provide image {}", archive_path.display(), image_tag);
+    }
+
+    Ok(true)
+}
+
+/// Find the archive of an image in the store manifest, the archive must stay
+/// inside the store and match its declared digest
+fn find_store_archive(store_path: &Path, image_tag: &str) -> Result<Option<PathBuf>, Error> {
+
+    let manifest_path = store_path.join(STORE_MANIFEST);
+    let manifest_content = fs::read_to_string(&manifest_path)
+        .with_context(|| format!("Could not read image store manifest {}", manifest_path.display()))?;
+    let manifest: StoreManifest = toml::from_str(&manifest_content)
+        .with_context(|| format!("Could not parse image store manifest {}", manifest_path.display()))?;
+
+    let archived_image = match manifest.images.iter().find(|archived| archived.image == image_tag) {
+        Some(archived_image) => archived_image,
+        None => {
+            warn!("Image {} is not declared in the image store manifest", image_tag);
+            return Ok(None);
+        }
+    };
+
+    let relative_archive = Path::new(&archived_image.archive);
+    let is_contained = relative_archive.components().all(|component| matches!(component, Component::Normal(_)));
+    if !is_contained {
+        bail!("Archive path {} should be relative to the image store", archived_image.archive);
+    }
+    let archive_path = store_path.join(relative_archive);
+
+    let archive_digest = compute_file_digest(&archive_path)
+        .with_context(|| format!("Could not read image archive {}", archive_path.display()))?;
+    let expected_digest = archived_image.digest.trim_start_matches("sha256:");
+    if !archive_digest.eq_ignore_ascii_case(expected_digest) {
+        bail!("Digest mismatch for image archive {} (expected {}, found {})", archive_path.display(), expected_digest, archive_digest);
+    }
+
+    Ok(Some(archive_path))
+}
+
+fn compute_file_digest(file_path: &Path) -> Result<String, Error> {
+
+    let mut archive_file = File::open(file_path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut archive_file, &mut hasher)?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Pull all function images in a background thread, errors are only logged
+/// since images will be pulled again when a scan requires them
+pub fn prewarm_images(config: &ConfigContainer, code_functions: &[CodeFunction]) {
+
+    let mut images: Vec<(String, PullPolicy)> = vec![];
+    for code_function in code_functions {
+
+        let pull_policy = code_function.pull_policy.unwrap_or(config.pull_policy);
+        for stage in &code_function.stages {
+
+            let is_listed = images.iter().any(|(image_tag, _)| image_tag == &stage.environment.base_image);
+            if !is_listed {
+                images.push((stage.environment.base_image.to_string(), pull_policy));
+            }
+        }
+    }
+
+    info!("Pre-warming {} container images in the background", images.len());
+
+    let thread_config = config.clone();
+    thread::spawn(move || {
+
+        for (image_tag, pull_policy) in images {
+
+            if let Err(err) = pull_image(&thread_config, &image_tag, pull_policy) {
+                warn!("Could not pre-warm image {} ({})", image_tag, err);
+            }
+        }
+        info!("Container images pre-warming done");
+    });
+}
+
+/// Mark an image as used by a function for the retention policy
+pub fn record_image_usage(workspace_path: &str, image_tag: &str) -> Result<(), Error> {
+
+    let mut usage = ImageUsage::load(workspace_path);
+    usage.images.insert(ImageReference::parse(image_tag).key(), current_timestamp());
+    usage.save(workspace_path)
+}
+
+/// Image of the containerd namespace considered by the retention policy
+#[derive(Clone, PartialEq, Debug)]
+struct StoredImage {
+
+    tag: String,
+
+    key: String,
+
+    last_used: u64,
+
+    size: u64
+
+}
+
+/// Remove images from the containerd namespace based on the retention policy,
+/// images never seen by the runner are considered as used on first encounter
+/// and images of the given functions are never removed
+pub fn collect_images(config: &ConfigContainer, workspace_path: &str, code_functions: &[CodeFunction]) -> Result<(), Error> {
+
+    if !config.retention.is_enabled() {
+        return Ok(());
+    }
+
+    let namespace_arg = format!("--namespace={}", config.namespace);
+    let output = Command::new("nerdctl")
+        .arg(&namespace_arg)
+        .arg("image")
+        .arg("ls")
+        .arg("--format")
+        .arg("{{json .}}")
+        .output()?;
+
+    if !output.status.success() {
+        let stderr_logs = String::from_utf8_lossy(&output.stderr);
+        bail!("Could not list images ({})", stderr_logs.trim());
+    }
+
+    let now = current_timestamp();
+    let mut usage = ImageUsage::load(workspace_path);
+    let mut images: Vec<StoredImage> = vec![];
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+
+        let listed_image: ListedImage = match serde_json::from_str(line) {
+            Ok(listed_image) => listed_image,
+            Err(_) => continue
+        };
+        if listed_image.repository == "<none>" || listed_image.tag == "<none>" {
+            continue;
+        }
+
+        let image_tag = format!("{}:{}", listed_image.repository, listed_image.tag);
+        let key = ImageReference::parse(&image_tag).key();
+        let last_used = *usage.images.entry(key.to_string()).or_insert(now);
+        let size = parse_image_size(&listed_image.size).unwrap_or_default();
+
+        images.push(StoredImage { tag: image_tag, key, last_used, size });
+    }
+
+    let protected_keys = function_image_keys(config, code_functions);
+
+    let removed_images = prune_images(config, images, &protected_keys, now, |image| {
+
+        info!("Removing container image {} based on retention policy", image.tag);
+        let remove_output = Command::new("nerdctl")
+            .arg(&namespace_arg)
+            .arg("image")
+            .arg("rm")
+            .arg(&image.tag)
+            .output();
+
+        match remove_output {
+            Ok(remove_output) if remove_output.status.success() => true,
+            Ok(remove_output) => {
+                let stderr_logs = String::from_utf8_lossy(&remove_output.stderr);
+                warn!("Could not remove image {} ({})", image.tag, stderr_logs.trim());
+                false
+            },
+            Err(err) => {
+                warn!("Could not remove image {} ({})", image.tag, err);
+                false
+            }
+        }
+    });
+
+    for image in removed_images {
+        usage.images.remove(&image.key);
+    }
+
+    usage.save(workspace_path)
+}
+
+/// Keys of the images referenced by functions, with and without mirror rewrite
+fn function_image_keys(config: &ConfigContainer, code_functions: &[CodeFunction]) -> HashSet<String> {
+
+    code_functions.iter()
+        .flat_map(|code_function| code_function.stages.iter())
+        .flat_map(|stage| {
+            let image_tag = &stage.environment.base_image;
+            [ImageReference::parse(image_tag).key(), ImageReference::parse(&resolve_image(config, image_tag)).key()]
+        })
+        .collect()
+}
+
+/// Expired images are removed, then least recently used images until the disk budget is respected,
+/// an image that could not be removed still counts toward the budget. Removed images are returned
+fn prune_images<F>(config: &ConfigContainer, mut images: Vec<StoredImage>, protected_keys: &HashSet<String>, now: u64, mut remove_image: F) -> Vec<StoredImage>
+where
+    F: FnMut(&StoredImage) -> bool
+{
+
+    images.sort_by_key(|image| image.last_used);
+    let mut total_size: u64 = images.iter().map(|image| image.size).sum();
+
+    let mut removed_images: Vec<StoredImage> = vec![];
+    for image in images {
+
+        if protected_keys.contains(&image.key) {
+            continue;
+        }
+
+        let is_expired = config.retention.unused_hours
+            .map(|unused_hours| now.saturating_sub(image.last_used) > unused_hours * 3600)
+            .unwrap_or(false);
+        let is_over_budget = config.retention.disk_budget
+            .map(|disk_budget| total_size > disk_budget * 1_000_000)
+            .unwrap_or(false);
+
+        if (is_expired || is_over_budget) && remove_image(&image) {
+            total_size = total_size.saturating_sub(image.size);
+            removed_images.push(image);
+        }
+    }
+
+    removed_images
+}
+
+/// Parse a human readable size given by nerdctl (such as "72.8 MiB")
+fn parse_image_size(raw_size: &str) -> Option<u64> {
+
+    let trimmed_size = raw_size.trim();
+    let unit_index = trimmed_size.find(|character: char| character.is_ascii_alphabetic())?;
+    let (raw_value, raw_unit) = trimmed_size.split_at(unit_index);
+
+    let value: f64 = raw_value.trim().parse().ok()?;
+    let multiplier: f64 = match raw_unit.trim() {
+        "B" => 1.0,
+        "kB" | "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "TiB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        _ => return None
+    };
+
+    Some((value * multiplier) as u64)
+}
+
+fn login_registry(namespace_arg: &str, registry: &str, username: &str, password: &str) -> Result<(), Error> {
+
+    // The password is given through stdin to avoid leaks in the process list
+    let mut login_process = Command::new("nerdctl")
+        .arg(namespace_arg)
+        .arg("login")
+        .arg("--username")
+        .arg(username)
+        .arg("--password-stdin")
+        .arg(registry)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = login_process.stdin.take() {
+        stdin.write_all(password.as_bytes())?;
+    }
+
+    let output = login_process.wait_with_output()?;
+    if !output.status.success() {
+        let stderr_logs = String::from_utf8_lossy(&output.stderr);
+        bail!("Registry login failed ({})", stderr_logs.trim());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+
+    use anyhow::Error;
+    use crate::components::config::{ConfigContainer, ConfigRetention};
+    use crate::test_utils::TempDir;
+    use super::{find_store_archive, parse_image_size, resolve_image, prune_images, ImageReference, StoredImage};
+
+    // SHA-256 of "archive"
+    const ARCHIVE_DIGEST: &str = "sha256:0eb3e36bfb24dcd9bb1d1bece1531216b59539a8fde17ee80224af0653c92aa3";
+
+    #[test]
+    fn should_find_store_archive() -> Result<(), Error> {
+
+        let store_dir = TempDir::new("store")?;
+        fs::write(store_dir.path().join("alpine.tar"), "archive")?;
+        fs::write(store_dir.path().join("manifest.toml"), format!(r#"
+            [[images]]
+            image = "alpine:3.20"
+            archive = "alpine.tar"
+            digest = "{}"
+        "#, ARCHIVE_DIGEST))?;
+
+        assert_eq!(find_store_archive(store_dir.path(), "alpine:3.20")?, Some(store_dir.path().join("alpine.tar")));
+        assert_eq!(find_store_archive(store_dir.path(), "alpine:3.21")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_refuse_mismatched_archive() -> Result<(), Error> {
+
+        let store_dir = TempDir::new("store")?;
+        fs::write(store_dir.path().join("alpine.tar"), "tampered archive")?;
+        fs::write(store_dir.path().join("manifest.toml"), format!(r#"
+            [[images]]
+            image = "alpine:3.20"
+            archive = "alpine.tar"
+            digest = "{}"
+        "#, ARCHIVE_DIGEST))?;
+
+        let err = find_store_archive(store_dir.path(), "alpine:3.20").err().map(|err| err.to_string()).unwrap_or_default();
+        assert!(err.starts_with("Digest mismatch"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_refuse_archive_outside_store() -> Result<(), Error> {
+
+        let store_dir = TempDir::new("store")?;
+        fs::write(store_dir.path().join("manifest.toml"), format!(r#"
+            [[images]]
+            image = "alpine:3.20"
+            archive = "../alpine.tar"
+            digest = "{}"
+        "#, ARCHIVE_DIGEST))?;
+
+        let err = find_store_archive(store_dir.path(), "alpine:3.20").err().map(|err| err.to_string()).unwrap_or_default();
+        assert_eq!(err, "Archive path ../alpine.tar should be relative to the image store");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_official_image() {
+
+        let reference = ImageReference::parse("alpine:3.20");
+
+        assert_eq!(reference, ImageReference {
+            registry: "docker.io".into(),
+            remote: "library/alpine:3.20".into()
+        });
+    }
+
+    #[test]
+    fn should_parse_user_image() {
+
+        let reference = ImageReference::parse("returntocorp/semgrep:latest");
+
+        assert_eq!(reference, ImageReference {
+            registry: "docker.io".into(),
+            remote: "returntocorp/semgrep:latest".into()
+        });
+    }
+
+    #[test]
+    fn should_parse_registry_image() {
+
+        let reference = ImageReference::parse("registry.local:5000/scanners/trivy:0.50");
+
+        assert_eq!(reference, ImageReference {
+            registry: "registry.local:5000".into(),
+            remote: "scanners/trivy:0.50".into()
+        });
+    }
+
+    #[test]
+    fn should_rewrite_mirrored_registry() {
+
+        let config = ConfigContainer {
+            mirrors: HashMap::from([("docker.io".to_string(), "mirror.internal".to_string())]),
+            ..Default::default()
+        };
+
+        assert_eq!(resolve_image(&config, "alpine:3.20"), "mirror.internal/library/alpine:3.20");
+        assert_eq!(resolve_image(&config, "ghcr.io/org/tool:1"), "ghcr.io/org/tool:1");
+    }
+
+    #[test]
+    fn should_parse_image_sizes() {
+
+        assert_eq!(parse_image_size("512 B"), Some(512));
+        assert_eq!(parse_image_size("72.5 MiB"), Some(76_021_760));
+        assert_eq!(parse_image_size("1.2GB"), Some(1_200_000_000));
+        assert_eq!(parse_image_size("unknown"), None);
+    }
+
+    #[test]
+    fn should_normalize_image_keys() {
+
+        assert_eq!(ImageReference::parse("alpine").key(), "docker.io/library/alpine:latest");
+        assert_eq!(ImageReference::parse("alpine:latest").key(), ImageReference::parse("docker.io/library/alpine").key());
+        assert_eq!(ImageReference::parse("docker.io/library/alpine:3").key(), ImageReference::parse("alpine:3").key());
+        assert_eq!(ImageReference::parse("registry.local:5000/trivy").key(), "registry.local:5000/trivy:latest");
+        assert_eq!(ImageReference::parse("alpine@sha256:abc").key(), "docker.io/library/alpine@sha256:abc");
+    }
+
+    #[test]
+    fn should_remove_least_recently_used_images_over_budget() {
+
+        let config = ConfigContainer {
+            retention: ConfigRetention { unused_hours: None, disk_budget: Some(250) },
+            ..Default::default()
+        };
+        let image = |name: &str, last_used: u64| StoredImage {
+            tag: format!("{}:1", name),
+            key: ImageReference::parse(&format!("{}:1", name)).key(),
+            last_used,
+            size: 100_000_000
+        };
+        let images = vec![image("recent", 300), image("oldest", 100), image("protected", 50), image("older", 200)];
+        let protected_keys = HashSet::from([ImageReference::parse("protected:1").key()]);
+
+        // 400Mb in use, the two least recently used images that are not protected are removed
+        let removed: Vec<String> = prune_images(&config, images.clone(), &protected_keys, 1_000, |_| true).into_iter()
+            .map(|image| image.tag)
+            .collect();
+        assert_eq!(vec!["oldest:1".to_string(), "older:1".to_string()], removed);
+
+        // An image that cannot be removed does not free its size, the next one is removed instead
+        let removed: Vec<String> = prune_images(&config, images, &protected_keys, 1_000, |image| image.tag != "oldest:1").into_iter()
+            .map(|image| image.tag)
+            .collect();
+        assert_eq!(vec!["older:1".to_string(), "recent:1".to_string()], removed);
+    }
+
+    #[test]
+    fn should_remove_expired_images() {
+
+        let config = ConfigContainer {
+            retention: ConfigRetention { unused_hours: Some(1), disk_budget: None },
+            ..Default::default()
+        };
+        let image = |name: &str, last_used: u64| StoredImage {
+            tag: name.to_string(),
+            key: ImageReference::parse(name).key(),
+            last_used,
+            size: 0
+        };
+        let images = vec![image("fresh", 9_000), image("stale", 1_000), image("stale-protected", 1_000)];
+        let protected_keys = HashSet::from([ImageReference::parse("stale-protected").key()]);
+
+        let removed = prune_images(&config, images, &protected_keys, 10_000, |_| true);
+        assert_eq!(vec![image("stale", 1_000)], removed);
+    }
+
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{CodeIssue, DiffStatus, MetricValue, ScanMetadata};
+use super::workspace::Workspace;
+
+const DIFF_METRIC_KEY: &str = "issue_diff";
+
+/// Issue kept in the index, enough to report it once resolved
+#[derive(Deserialize, Serialize, Clone)]
+struct IndexedIssue {
+
+    fingerprint: String,
+
+    name: String,
+
+    severity: Option<String>,
+
+    #[serde(rename = "ruleId")]
+    rule_id: Option<String>,
+
+    path: Option<String>
+
+}
+
+/// Fingerprints of the issues found by the last successful scan of a function on a repository
+#[derive(Deserialize, Serialize, Default)]
+pub struct IssueIndex {
+
+    issues: Vec<IndexedIssue>
+
+}
+
+impl IssueIndex {
+
+    /// Load the index, None is returned when the function never succeeded on the repository
+    /// (or on the requested reference, each reference keeps its own index)
+    pub fn load(workspace: &Workspace, function_id: &str, repository_id: &str, git_ref: Option<&str>) -> Result<Option<IssueIndex>, Error> {
+
+        match workspace.read_index(function_id, repository_id, git_ref)? {
+            Some(index_content) => Ok(Some(serde_json::from_str(&index_content)?)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn from_issues(issues: &[CodeIssue]) -> IssueIndex {
+
+        let mut known_fingerprints: HashSet<&str> = HashSet::new();
+        let indexed_issues = issues.iter()
+            .filter(|issue| issue.diff_status != Some(DiffStatus::Resolved))
+            .filter_map(|issue| {
+
+                let fingerprint = issue.fingerprint.as_deref()?;
+                if !known_fingerprints.insert(fingerprint) {
+                    return None;
+                }
+
+                Some(IndexedIssue {
+                    fingerprint: fingerprint.to_string(),
+                    name: issue.name.to_string(),
+                    severity: issue.severity.clone(),
+                    rule_id: issue.rule_id.clone(),
+                    path: issue.path.clone()
+                })
+            })
+            .collect();
+
+        IssueIndex { issues: indexed_issues }
+    }
+
+    pub fn save(&self, workspace: &Workspace, function_id: &str, repository_id: &str, git_ref: Option<&str>) -> Result<(), Error> {
+        workspace.write_index(function_id, repository_id, git_ref, &serde_json::to_string(self)?)
+    }
+
+    /// Classify issues as new or unchanged, issues of the index missing from the
+    /// current scan are returned as resolved
+    pub fn diff(&self, issues: &mut [CodeIssue]) -> Vec<CodeIssue> {
+
+        let indexed_fingerprints: HashSet<&str> = self.issues.iter()
+            .map(|indexed_issue| indexed_issue.fingerprint.as_str())
+            .collect();
+        let mut current_fingerprints: HashSet<String> = HashSet::new();
+
+        for issue in issues.iter_mut() {
+
+            let fingerprint = issue.fingerprint.clone().unwrap_or_default();
+            issue.diff_status = match indexed_fingerprints.contains(fingerprint.as_str()) {
+                true => Some(DiffStatus::Unchanged),
+                false => Some(DiffStatus::New)
+            };
+            current_fingerprints.insert(fingerprint);
+        }
+
+        self.issues.iter()
+            .filter(|indexed_issue| !current_fingerprints.contains(&indexed_issue.fingerprint))
+            .map(|indexed_issue| CodeIssue {
+                name: indexed_issue.name.to_string(),
+                severity: indexed_issue.severity.clone(),
+                rule_id: indexed_issue.rule_id.clone(),
+                path: indexed_issue.path.clone(),
+                fingerprint: Some(indexed_issue.fingerprint.to_string()),
+                diff_status: Some(DiffStatus::Resolved),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+}
+
+/// Summary of the issue classification (new, unchanged and resolved counts)
+pub fn diff_metric(issues: &[CodeIssue]) -> ScanMetadata {
+
+    let mut status_counts: BTreeMap<String, MetricValue> = BTreeMap::new();
+
+    for (status, status_name) in [(DiffStatus::New, "new"), (DiffStatus::Unchanged, "unchanged"), (DiffStatus::Resolved, "resolved")] {
+        let status_count = issues.iter().filter(|issue| issue.diff_status == Some(status)).count();
+        status_counts.insert(status_name.to_string(), MetricValue::Number(status_count as i64));
+    }
+
+    ScanMetadata {
+        key: DIFF_METRIC_KEY.to_string(),
+        description: "Issues compared to the last successful scan".to_string(),
+        value: MetricValue::Table(status_counts),
+        unit: Some("issues".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::models::{DiffStatus, MetricValue};
+    use crate::test_utils::build_issue;
+    use super::{diff_metric, IssueIndex};
+
+    #[test]
+    fn should_classify_issues() {
+
+        let previous_index = IssueIndex::from_issues(&[build_issue("Weak hash", "a1"), build_issue("Eval", "b2"), build_issue("Eval", "b2")]);
+        let mut issues = vec![build_issue("Eval", "b2"), build_issue("Hardcoded password", "c3")];
+
+        let resolved_issues = previous_index.diff(&mut issues);
+
+        assert_eq!(issues[0].diff_status, Some(DiffStatus::Unchanged));
+        assert_eq!(issues[1].diff_status, Some(DiffStatus::New));
+        assert_eq!(resolved_issues.len(), 1);
+        assert_eq!(resolved_issues[0].name, "Weak hash");
+
+        issues.extend(resolved_issues);
+        let metric = diff_metric(&issues);
+        match metric.value {
+            MetricValue::Table(status_counts) => {
+                assert_eq!(status_counts["new"], MetricValue::Number(1));
+                assert_eq!(status_counts["resolved"], MetricValue::Number(1));
+            },
+            _ => panic!("Expected a status table")
+        }
+
+        assert_eq!(IssueIndex::from_issues(&issues).issues.len(), 2);
+    }
+
+}
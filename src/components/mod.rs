@@ -1,4 +1,13 @@
 pub mod workspace;
 pub mod scheduler;
 pub mod config;
-pub mod container;
\ No newline at end of file
+pub mod container;
+pub mod image;
+pub mod proxy;
+pub mod tracker;
+pub mod usage;
+pub mod results;
+pub mod artifacts;
+pub mod suppressions;
+pub mod gates;
+pub mod index;
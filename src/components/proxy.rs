@@ -0,0 +1,631 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+use log::{debug, info, warn};
+
+use super::config::ConfigContainer;
+
+const MAX_REQUEST_HEAD: usize = 16_384;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HEAD_TIMEOUT: Duration = Duration::from_secs(30);     // Clients must send their request head within this delay
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);    // Forwarded connections without traffic are closed
+const START_RETRY_PERIOD: Duration = Duration::from_secs(10);
+const EGRESS_LABEL: &str = "chicon.egress";
+const EGRESS_LABEL_VALUE: &str = "internal";
+
+/// Egress destination allowed for a function, either a host name (with an
+/// optional "*." wildcard prefix) or an IP network in CIDR notation
+#[derive(PartialEq, Debug)]
+pub enum EgressRule {
+
+    Host(String),
+
+    Network(IpAddr, u8)
+
+}
+
+impl EgressRule {
+
+    pub fn parse(raw_rule: &str) -> Result<EgressRule, Error> {
+
+        let trimmed_rule = raw_rule.trim();
+
+        if let Some((raw_address, raw_prefix)) = trimmed_rule.split_once('/') {
+
+            let address: IpAddr = raw_address.parse().with_context(|| format!("Invalid network address in egress rule '{}'", raw_rule))?;
+            let prefix: u8 = raw_prefix.parse().with_context(|| format!("Invalid prefix in egress rule '{}'", raw_rule))?;
+
+            let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+            if prefix > max_prefix {
+                bail!("Prefix of egress rule '{}' is out of range", raw_rule);
+            }
+            return Ok(EgressRule::Network(address, prefix));
+        }
+
+        if let Ok(address) = trimmed_rule.parse::<IpAddr>() {
+            let prefix = if address.is_ipv4() { 32 } else { 128 };
+            return Ok(EgressRule::Network(address, prefix));
+        }
+
+        if trimmed_rule.is_empty() {
+            bail!("Egress rule should not be empty");
+        }
+        Ok(EgressRule::Host(trimmed_rule.to_lowercase()))
+    }
+
+    pub fn matches_host(&self, host: &str) -> bool {
+
+        match self {
+            EgressRule::Host(rule_host) => {
+
+                let host = host.to_lowercase();
+                match rule_host.strip_prefix("*.") {
+                    Some(domain) => host.ends_with(&format!(".{}", domain)),
+                    None => &host == rule_host
+                }
+            },
+            EgressRule::Network(_, _) => false
+        }
+    }
+
+    pub fn matches_address(&self, candidate: &IpAddr) -> bool {
+
+        match (self, candidate) {
+            (EgressRule::Network(IpAddr::V4(network), prefix), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*prefix)).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(*address) & mask
+            },
+            (EgressRule::Network(IpAddr::V6(network), prefix), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*prefix)).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(*address) & mask
+            },
+            _ => false
+        }
+    }
+
+}
+
+#[derive(Default)]
+struct EgressPolicy {
+
+    rules: Vec<EgressRule>,
+
+    denied: Vec<String>,
+
+    failed: Vec<String>     // Allowed destinations that could not be resolved or reached
+
+}
+
+/// Connections of a function that did not go through, see [`EgressProxy::reset`]
+#[derive(Default)]
+pub struct EgressReport {
+
+    pub denied: Vec<String>,
+
+    pub failed: Vec<String>
+
+}
+
+impl EgressPolicy {
+
+    fn is_host_allowed(&self, host: &str) -> bool {
+        self.rules.iter().any(|rule| rule.matches_host(host))
+    }
+
+    /// Keep the resolved addresses of a destination allowed by the policy
+    fn filter(&self, host: &str, addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+
+        let is_host_allowed = self.is_host_allowed(host);
+
+        addresses.into_iter()
+            .filter(|address: &SocketAddr| is_host_allowed || self.rules.iter().any(|rule| rule.matches_address(&address.ip())))
+            .collect()
+    }
+
+}
+
+/// Filtering HTTP(S) proxy managed by the runner, functions with an egress
+/// allowlist can only reach the outside world through this proxy
+pub struct EgressProxy {
+
+    policy: Arc<Mutex<EgressPolicy>>,
+
+    is_listening: Arc<AtomicBool>,
+
+    network: String,
+
+    proxy_url: String
+
+}
+
+impl EgressProxy {
+
+    /// Start the proxy in a background thread, the network creation and the
+    /// listener bind are retried so that a failure never stops the runner
+    pub fn start(config: &ConfigContainer) -> Option<EgressProxy> {
+
+        let egress = config.egress.as_ref()?;
+
+        // Only containers of the internal network should reach the proxy, not the whole LAN
+        let proxy_listen = egress.proxy_listen.as_ref().unwrap_or(&egress.proxy_address).to_string();
+        let thread_config = config.clone();
+
+        let policy: Arc<Mutex<EgressPolicy>> = Arc::new(Mutex::new(EgressPolicy::default()));
+        let is_listening = Arc::new(AtomicBool::new(false));
+        let thread_policy = policy.clone();
+        let thread_listening = is_listening.clone();
+
+        thread::spawn(move || {
+
+            let listener = loop {
+
+                let bind_result = ensure_network(&thread_config).and_then(|_| bind_listener(&proxy_listen));
+                match bind_result {
+                    Ok(listener) => break listener,
+                    Err(err) => {
+                        warn!("Could not start egress proxy on {}, retrying in {}s ({:#})", proxy_listen, START_RETRY_PERIOD.as_secs(), err);
+                        thread::sleep(START_RETRY_PERIOD);
+                    }
+                }
+            };
+            info!("Egress proxy listening on {}", proxy_listen);
+            thread_listening.store(true, Ordering::Relaxed);
+
+            for incoming in listener.incoming() {
+
+                let client = match incoming {
+                    Ok(client) => client,
+                    Err(err) => {
+                        warn!("Egress proxy could not accept connection ({})", err);
+                        continue;
+                    }
+                };
+
+                let connection_policy = thread_policy.clone();
+                thread::spawn(move || {
+                    if let Err(err) = handle_connection(client, connection_policy) {
+                        debug!("Egress proxy connection closed with error ({})", err);
+                    }
+                });
+            }
+        });
+
+        let proxy = EgressProxy {
+            policy,
+            is_listening,
+            network: egress.network.to_string(),
+            proxy_url: format!("http://{}", egress.proxy_address)
+        };
+        Some(proxy)
+    }
+
+    /// Replace the active allowlist, any previously denied connection is dropped
+    pub fn allow(&self, raw_rules: &[String]) -> Result<(), Error> {
+
+        if !self.is_listening.load(Ordering::Relaxed) {
+            bail!("Egress proxy is not listening yet, see the runner logs");
+        }
+
+        let rules = raw_rules.iter()
+            .map(|raw_rule| EgressRule::parse(raw_rule))
+            .collect::<Result<Vec<EgressRule>, Error>>()?;
+
+        let mut policy = self.policy.lock().map_err(|_| anyhow!("Egress policy lock is poisoned"))?;
+        policy.rules = rules;
+        policy.denied.clear();
+        policy.failed.clear();
+
+        Ok(())
+    }
+
+    /// Deny all destinations and return the connections denied or failed since the last allow
+    pub fn reset(&self) -> EgressReport {
+
+        match self.policy.lock() {
+            Ok(mut policy) => {
+                policy.rules.clear();
+                EgressReport {
+                    denied: mem::take(&mut policy.denied),
+                    failed: mem::take(&mut policy.failed)
+                }
+            },
+            Err(_) => EgressReport::default()
+        }
+    }
+
+    pub fn container_args(&self) -> Vec<String> {
+
+        let mut args: Vec<String> = vec!["--network".into(), self.network.to_string()];
+
+        for variable in ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"] {
+            args.push("--env".into());
+            args.push(format!("{}={}", variable, self.proxy_url));
+        }
+
+        args
+    }
+
+}
+
+/// Create the dedicated egress network when missing, the network is internal
+/// (no default route or masquerading) so that the proxy is the only way out
+fn ensure_network(config: &ConfigContainer) -> Result<(), Error> {
+
+    let egress = match &config.egress {
+        Some(egress) => egress,
+        None => return Ok(())
+    };
+    let namespace_arg = format!("--namespace={}", config.namespace);
+
+    let inspect_output = Command::new("nerdctl")
+        .arg(&namespace_arg)
+        .arg("network")
+        .arg("inspect")
+        .arg("--format")
+        .arg("{{json .Labels}}")
+        .arg(&egress.network)
+        .output()?;
+    if inspect_output.status.success() {
+
+        let labels = String::from_utf8_lossy(&inspect_output.stdout);
+        if !labels.contains(&format!("\"{}\":\"{}\"", EGRESS_LABEL, EGRESS_LABEL_VALUE)) {
+            bail!("Egress network {} is not an internal network created by the runner, remove it to let the runner create it", egress.network);
+        }
+        return Ok(());
+    }
+
+    info!("Creating internal egress network {}", egress.network);
+    let create_output = Command::new("nerdctl")
+        .arg(&namespace_arg)
+        .args(network_create_args(&egress.network, egress.subnet.as_deref()))
+        .output()?;
+    if !create_output.status.success() {
+        let stderr_logs = String::from_utf8_lossy(&create_output.stderr);
+        bail!("Could not create egress network {} ({})", egress.network, stderr_logs.trim());
+    }
+
+    Ok(())
+}
+
+/// Bind the proxy listener, the address may belong to a bridge that only
+/// appears once a container joins the network (hence IP_FREEBIND)
+fn bind_listener(proxy_listen: &str) -> Result<TcpListener, Error> {
+
+    let address = proxy_listen.to_socket_addrs()?.next()
+        .with_context(|| format!("Could not resolve proxy address {}", proxy_listen))?;
+
+    let (domain, level, option) = match address {
+        SocketAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_IP, libc::IP_FREEBIND),
+        SocketAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_IPV6, libc::IPV6_FREEBIND)
+    };
+
+    // SAFETY: the descriptor is owned right after creation and closed on drop
+    let socket = unsafe {
+        let raw_socket = libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0);
+        if raw_socket < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        OwnedFd::from_raw_fd(raw_socket)
+    };
+    let raw_socket = socket.as_raw_fd();
+
+    let enabled: libc::c_int = 1;
+    for (option_level, option_name) in [(libc::SOL_SOCKET, libc::SO_REUSEADDR), (level, option)] {
+        // SAFETY: the option value is a valid c_int for the duration of the call
+        let result = unsafe {
+            libc::setsockopt(raw_socket, option_level, option_name, &enabled as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+
+    let (storage, storage_length) = socket_address(&address);
+    // SAFETY: the storage holds a socket address of the given length
+    let result = unsafe { libc::bind(raw_socket, &storage as *const libc::sockaddr_storage as *const libc::sockaddr, storage_length) };
+    if result < 0 {
+        return Err(anyhow!(io::Error::last_os_error()).context(format!("Could not bind egress proxy on {}", proxy_listen)));
+    }
+    // SAFETY: the descriptor is a bound stream socket
+    if unsafe { libc::listen(raw_socket, 128) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(TcpListener::from(socket))
+}
+
+fn socket_address(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+
+    // SAFETY: an all-zero sockaddr_storage is a valid value
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+
+    let storage_length = match address {
+        SocketAddr::V4(address) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for any socket address
+            let ipv4_address = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+            ipv4_address.sin_family = libc::AF_INET as libc::sa_family_t;
+            ipv4_address.sin_port = address.port().to_be();
+            ipv4_address.sin_addr = libc::in_addr { s_addr: u32::from(*address.ip()).to_be() };
+            mem::size_of::<libc::sockaddr_in>()
+        },
+        SocketAddr::V6(address) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for any socket address
+            let ipv6_address = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+            ipv6_address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            ipv6_address.sin6_port = address.port().to_be();
+            ipv6_address.sin6_addr = libc::in6_addr { s6_addr: address.ip().octets() };
+            ipv6_address.sin6_scope_id = address.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+
+    (storage, storage_length as libc::socklen_t)
+}
+
+fn network_create_args(network: &str, subnet: Option<&str>) -> Vec<String> {
+
+    let mut args: Vec<String> = vec![
+        "network".into(),
+        "create".into(),
+        "--internal".into(),
+        "--label".into(),
+        format!("{}={}", EGRESS_LABEL, EGRESS_LABEL_VALUE)
+    ];
+
+    if let Some(subnet) = subnet {
+        args.push("--subnet".into());
+        args.push(subnet.to_string());
+    }
+
+    args.push(network.to_string());
+    args
+}
+
+fn handle_connection(mut client: TcpStream, policy: Arc<Mutex<EgressPolicy>>) -> Result<(), Error> {
+
+    client.set_read_timeout(Some(HEAD_TIMEOUT))?;
+    client.set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+    // Read the request head, body bytes received in the same chunks are kept
+    let mut buffer: Vec<u8> = vec![];
+    let head_length = loop {
+
+        let mut chunk = [0; 4096];
+        let read_size = client.read(&mut chunk)?;
+        if read_size == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&chunk[..read_size]);
+
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if buffer.len() > MAX_REQUEST_HEAD {
+            bail!("Request head is too large");
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..head_length]).to_string();
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut request_parts = request_line.split(' ');
+    let method = request_parts.next().unwrap_or_default();
+    let target = request_parts.next().unwrap_or_default();
+    let version = request_parts.next().unwrap_or("HTTP/1.1");
+
+    let is_tunnel = method.eq_ignore_ascii_case("CONNECT");
+    let (host, port, origin_path) = if is_tunnel {
+        let (host, port) = split_authority(target, 443)?;
+        (host, port, String::new())
+    }
+    else {
+        let remainder = match target.strip_prefix("http://") {
+            Some(remainder) => remainder,
+            None => {
+                client.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")?;
+                bail!("Unsupported proxy target {}", target);
+            }
+        };
+        let (authority, path) = match remainder.find('/') {
+            Some(index) => remainder.split_at(index),
+            None => (remainder, "/")
+        };
+        let (host, port) = split_authority(authority, 80)?;
+        (host, port, path.to_string())
+    };
+
+    // The lookup is done before locking so that a slow DNS does not block other connections
+    let resolution = (host.as_str(), port).to_socket_addrs()
+        .map(|addresses| addresses.collect::<Vec<SocketAddr>>());
+
+    let allowed_addresses = {
+        let mut locked_policy = policy.lock().map_err(|_| anyhow!("Egress policy lock is poisoned"))?;
+
+        // An allowed host that cannot be resolved is an upstream failure, not a denial
+        let resolved_addresses = match resolution {
+            Ok(resolved_addresses) => resolved_addresses,
+            Err(err) if locked_policy.is_host_allowed(&host) => {
+                locked_policy.failed.push(format!("{} {}:{} (resolution failed: {})", method, host, port, err));
+                drop(locked_policy);
+                client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")?;
+                bail!("Could not resolve {} ({})", host, err);
+            },
+            Err(_) => vec![]
+        };
+        let allowed_addresses = locked_policy.filter(&host, resolved_addresses);
+
+        if allowed_addresses.is_empty() {
+            locked_policy.denied.push(format!("{} {}:{}", method, host, port));
+        }
+        allowed_addresses
+    };
+
+    if allowed_addresses.is_empty() {
+        client.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")?;
+        return Ok(());
+    }
+
+    // Only the checked addresses are used to avoid DNS rebinding
+    let upstream = allowed_addresses.iter()
+        .find_map(|address| TcpStream::connect_timeout(address, CONNECT_TIMEOUT).ok());
+    let mut upstream = match upstream {
+        Some(upstream) => upstream,
+        None => {
+            if let Ok(mut locked_policy) = policy.lock() {
+                locked_policy.failed.push(format!("{} {}:{} (connection failed)", method, host, port));
+            }
+            client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n")?;
+            bail!("Could not connect to {}:{}", host, port);
+        }
+    };
+
+    // Forwarded connections are closed once idle, in both directions
+    client.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    upstream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    upstream.set_write_timeout(Some(IDLE_TIMEOUT))?;
+
+    if is_tunnel {
+        client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+        upstream.write_all(&buffer[head_length..])?;
+    }
+    else {
+
+        // Forward the request with an origin-form target
+        let header_lines: Vec<&str> = head.lines().skip(1)
+            .filter(|line| !line.is_empty() && !line.to_lowercase().starts_with("proxy-"))
+            .collect();
+        let forwarded_head = format!("{} {} {}\r\n{}\r\n\r\n", method, origin_path, version, header_lines.join("\r\n"));
+
+        upstream.write_all(forwarded_head.as_bytes())?;
+        upstream.write_all(&buffer[head_length..])?;
+    }
+
+    pipe_streams(client, upstream)
+}
+
+fn split_authority(authority: &str, default_port: u16) -> Result<(String, u16), Error> {
+
+    // Bracketed IPv6 addresses such as [::1]:443
+    if let Some(remainder) = authority.strip_prefix('[') {
+
+        let (host, port_part) = remainder.split_once(']').context("Invalid IPv6 authority")?;
+        let port = match port_part.strip_prefix(':') {
+            Some(raw_port) => raw_port.parse()?,
+            None => default_port
+        };
+        return Ok((host.to_string(), port));
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, raw_port)) => Ok((host.to_string(), raw_port.parse()?)),
+        None => Ok((authority.to_string(), default_port))
+    }
+}
+
+fn pipe_streams(client: TcpStream, upstream: TcpStream) -> Result<(), Error> {
+
+    let mut client_reader = client.try_clone()?;
+    let mut upstream_writer = upstream.try_clone()?;
+
+    let upload = thread::spawn(move || {
+        io::copy(&mut client_reader, &mut upstream_writer).ok();
+        upstream_writer.shutdown(Shutdown::Write).ok();
+    });
+
+    let mut upstream_reader = upstream;
+    let mut client_writer = client;
+    io::copy(&mut upstream_reader, &mut client_writer).ok();
+    client_writer.shutdown(Shutdown::Write).ok();
+
+    upload.join().ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::net::IpAddr;
+
+    use anyhow::Error;
+    use super::{bind_listener, network_create_args, EgressRule};
+
+    #[test]
+    fn should_match_exact_host() -> Result<(), Error> {
+
+        let rule = EgressRule::parse("registry.npmjs.org")?;
+
+        assert!(rule.matches_host("registry.npmjs.org"));
+        assert!(rule.matches_host("Registry.NPMJS.org"));
+        assert!(!rule.matches_host("evil-registry.npmjs.org"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_match_wildcard_host() -> Result<(), Error> {
+
+        let rule = EgressRule::parse("*.github.com")?;
+
+        assert!(rule.matches_host("api.github.com"));
+        assert!(!rule.matches_host("github.com"));
+        assert!(!rule.matches_host("github.com.evil.org"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_match_network() -> Result<(), Error> {
+
+        let rule = EgressRule::parse("10.20.0.0/16")?;
+
+        assert!(rule.matches_address(&"10.20.4.2".parse::<IpAddr>()?));
+        assert!(!rule.matches_address(&"10.21.0.1".parse::<IpAddr>()?));
+        assert!(!rule.matches_address(&"::1".parse::<IpAddr>()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_match_single_address() -> Result<(), Error> {
+
+        let rule = EgressRule::parse("2001:db8::1")?;
+
+        assert!(rule.matches_address(&"2001:db8::1".parse::<IpAddr>()?));
+        assert!(!rule.matches_address(&"2001:db8::2".parse::<IpAddr>()?));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_reject_invalid_prefix() {
+
+        assert!(EgressRule::parse("10.0.0.0/33").is_err());
+    }
+
+    #[test]
+    fn should_create_internal_network() {
+
+        let args = network_create_args("chicon-egress", Some("10.89.20.0/24"));
+
+        assert_eq!(vec!["network", "create", "--internal", "--label", "chicon.egress=internal", "--subnet", "10.89.20.0/24", "chicon-egress"], args);
+    }
+
+    #[test]
+    fn should_bind_before_bridge_exists() -> Result<(), Error> {
+
+        // TEST-NET-1 is never assigned to a local interface
+        let listener = bind_listener("192.0.2.1:0")?;
+
+        assert_eq!(listener.local_addr()?.ip().to_string(), "192.0.2.1");
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,670 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Error, Result};
+use log::warn;
+use serde::Deserialize;
+
+use crate::models::{CodeFunction, CodeIssue, FailureReason, FunctionOutput, MetricType, MetricValue, ResultValidation, SbomComponent, Scan, ScanComponents, ScanMetadata};
+use crate::parsers::sbom::{detect_sbom, parse_sbom};
+use crate::utils::open_no_follow;
+use super::config::ConfigWorkspace;
+
+// Result files by order of precedence when a function writes several formats
+pub const METRICS_FILES: [&str; 2] = ["data.toml", "data.json"];
+pub const ISSUES_FILES: [&str; 3] = ["issues.toml", "issues.json", "issues.ndjson"];
+
+const BYTES_PER_MB: u64 = 1_000_000;
+
+const SBOM_COMPONENTS_KEY: &str = "sbom_components";
+const SBOM_LICENSES_KEY: &str = "sbom_licenses";
+
+#[derive(Deserialize)]
+struct IssueContainer {
+
+    issues: Vec<CodeIssue>
+
+}
+
+/// Reads function results without trusting the container output: symbolic links
+/// and special files are refused, sizes are capped and contents must be UTF-8
+pub struct ResultReader {
+
+    result_path: PathBuf,
+
+    file_limit: u64,
+
+    total_limit: u64,
+
+    read_files: RefCell<HashSet<String>>,  // Files read several times only count once
+
+    total_size: Cell<u64>
+
+}
+
+impl ResultReader {
+
+    pub fn new(result_path: PathBuf, config: &ConfigWorkspace) -> ResultReader {
+
+        ResultReader {
+            result_path,
+            file_limit: config.result_file_limit * BYTES_PER_MB,
+            total_limit: config.result_total_limit * BYTES_PER_MB,
+            read_files: RefCell::new(HashSet::new()),
+            total_size: Cell::new(0)
+        }
+    }
+
+    /// Regular files at the root of the result directory
+    pub fn list_files(&self) -> Vec<String> {
+        self.list_matching(|file_type| file_type.is_file())
+    }
+
+    /// Entries of any type at the root of the result directory, used for the well-known
+    /// result files so that links or special files are refused instead of ignored
+    pub fn list_entries(&self) -> Vec<String> {
+        self.list_matching(|_| true)
+    }
+
+    fn list_matching(&self, predicate: impl Fn(&fs::FileType) -> bool) -> Vec<String> {
+
+        let mut file_names: Vec<String> = fs::read_dir(&self.result_path).into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| predicate(&file_type)))
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+
+        file_names.sort();
+        file_names
+    }
+
+    /// Read a file relative to the result directory, None is returned when the file does not exist
+    pub fn read_file(&self, relative_path: &str) -> Result<Option<String>, Error> {
+
+        let file_path = Path::new(relative_path);
+        if relative_path.is_empty() || !file_path.components().all(|component| matches!(component, Component::Normal(_))) {
+            bail!("path should be relative to the result directory");
+        }
+
+        // The last component is opened without following links, parent directories are checked beforehand
+        for parent_path in file_path.ancestors().skip(1).filter(|parent_path| !parent_path.as_os_str().is_empty()) {
+            match fs::symlink_metadata(self.result_path.join(parent_path)) {
+                Ok(metadata) if metadata.is_dir() => {},
+                Ok(_) => bail!("{} is not a directory", parent_path.display()),
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into())
+            }
+        }
+
+        let file = match open_no_follow(&self.result_path.join(file_path)) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) if err.raw_os_error() == Some(libc::ELOOP) => bail!("symbolic links are not accepted"),
+            Err(err) => return Err(err.into())
+        };
+
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            bail!("only regular files are accepted");
+        }
+
+        let is_counted = self.read_files.borrow().contains(relative_path);
+        if metadata.len() > self.file_limit {
+            bail!("{} bytes exceed the {}Mb file limit", metadata.len(), self.file_limit / BYTES_PER_MB);
+        }
+        if !is_counted && self.total_size.get() + metadata.len() > self.total_limit {
+            bail!("the {}Mb limit of all result files is reached", self.total_limit / BYTES_PER_MB);
+        }
+
+        // The file could still grow while being read
+        let mut content: Vec<u8> = vec![];
+        file.take(self.file_limit + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > self.file_limit {
+            bail!("file exceeds the {}Mb file limit", self.file_limit / BYTES_PER_MB);
+        }
+
+        if !is_counted {
+            self.total_size.set(self.total_size.get() + content.len() as u64);
+            self.read_files.borrow_mut().insert(relative_path.to_string());
+        }
+
+        let text_content = String::from_utf8(content)
+            .map_err(|err| anyhow!("invalid UTF-8 content at byte {}", err.utf8_error().valid_up_to()))?;
+        Ok(Some(text_content))
+    }
+
+}
+
+/// Read a result file ("result/[file]"), refused files are reported as problems
+fn read_checked(result_reader: &ResultReader, result_file: &str, problems: &mut Vec<String>) -> Option<String> {
+
+    let relative_path = result_file.strip_prefix("result/").unwrap_or(result_file);
+
+    match result_reader.read_file(relative_path) {
+        Ok(potential_content) => potential_content,
+        Err(err) => {
+            problems.push(format!("{}: file refused, {:#}", result_file, err));
+            None
+        }
+    }
+}
+
+/// Keep the first issues and metrics within the runner limits, the scan is flagged when truncated
+pub fn limit_results(config: &ConfigWorkspace, scan: &mut Scan, potential_issues: &mut Option<Vec<CodeIssue>>) {
+
+    if scan.results.len() > config.max_metrics {
+
+        scan.logs.push_str(&format!("[chicon] {} metrics found, only the first {} are kept\n", scan.results.len(), config.max_metrics));
+        scan.results.sort_by(|first_metric, second_metric| first_metric.key.cmp(&second_metric.key));
+        scan.results.truncate(config.max_metrics);
+        scan.is_truncated = true;
+    }
+
+    if let Some(issues) = potential_issues.as_mut().filter(|issues| issues.len() > config.max_issues) {
+
+        scan.logs.push_str(&format!("[chicon] {} issues found, only the first {} are kept\n", issues.len(), config.max_issues));
+        issues.truncate(config.max_issues);
+        scan.is_truncated = true;
+    }
+}
+
+/// Read the metrics written by a function in result/data.toml (or data.json),
+/// validation problems are collected and the metrics that could be read are returned
+pub fn read_metrics(result_reader: &ResultReader, outputs: &[FunctionOutput], problems: &mut Vec<String>) -> Vec<ScanMetadata> {
+
+    let result_files = result_reader.list_entries();
+    let metrics_file = format!("result/{}", select_result_file(&result_files, &METRICS_FILES, problems).unwrap_or(METRICS_FILES[0]));
+
+    let metrics: HashMap<String, MetricValue> = match read_checked(result_reader, &metrics_file, problems) {
+        Some(content) => {
+
+            let parsed_metrics = if metrics_file.ends_with(".json") {
+                serde_json::from_str(&content).map_err(|err| describe_json_error(&metrics_file, &err))
+            }
+            else {
+                toml::from_str(&content).map_err(|err| describe_toml_error(&metrics_file, &content, &err))
+            };
+
+            match parsed_metrics {
+                Ok(metrics) => metrics,
+                Err(problem) => {
+                    problems.push(problem);
+                    return vec![];
+                }
+            }
+        },
+        None => HashMap::new()
+    };
+
+    problems.extend(validate_metric_keys(&metrics_file, &metrics, outputs));
+
+    // Undeclared metrics are always kept, the validation mode only decides whether the scan fails
+    let mut results: Vec<ScanMetadata> = vec![];
+    for (result_key, result_value) in metrics {
+
+        let output = match outputs.iter().find(|output| output.key == result_key) {
+            Some(output) => output,
+            None => {
+                results.push(ScanMetadata { key: result_key, description: "".to_string(), value: result_value, unit: None });
+                continue;
+            }
+        };
+
+        match coerce_metric(result_value, output) {
+            Ok(value) => results.push(ScanMetadata {
+                key: result_key,
+                description: output.description.to_string(),
+                value,
+                unit: output.unit.clone()
+            }),
+            Err(problem) => problems.push(format!("{}: output \"{}\" {}", metrics_file, result_key, problem))
+        }
+    }
+
+    results
+}
+
+/// Convert a metric to the declared output type and check its bounds, lossless
+/// conversions (such as an integer for a float output) are accepted
+fn coerce_metric(value: MetricValue, output: &FunctionOutput) -> Result<MetricValue, String> {
+
+    let metric_type = match output.metric_type {
+        Some(metric_type) => metric_type,
+        None => return Ok(value)
+    };
+
+    let found_type = value.type_name();
+    let coerced_value = match (metric_type, value) {
+        (MetricType::Number, MetricValue::Number(number)) => Some(MetricValue::Number(number)),
+        (MetricType::Number, MetricValue::Float(float)) if float.fract() == 0.0 && float.abs() < i64::MAX as f64 => Some(MetricValue::Number(float as i64)),
+        (MetricType::Number, MetricValue::Text(text)) => text.trim().parse().ok().map(MetricValue::Number),
+        (MetricType::Float, MetricValue::Float(float)) => Some(MetricValue::Float(float)),
+        (MetricType::Float, MetricValue::Number(number)) => Some(MetricValue::Float(number as f64)),
+        (MetricType::Float, MetricValue::Text(text)) => text.trim().parse().ok().filter(|float: &f64| float.is_finite()).map(MetricValue::Float),
+        (MetricType::Text, MetricValue::Text(text)) => Some(MetricValue::Text(text)),
+        (MetricType::Text, MetricValue::Number(number)) => Some(MetricValue::Text(number.to_string())),
+        (MetricType::Text, MetricValue::Float(float)) => Some(MetricValue::Text(float.to_string())),
+        (MetricType::Text, MetricValue::Boolean(boolean)) => Some(MetricValue::Text(boolean.to_string())),
+        (MetricType::Boolean, MetricValue::Boolean(boolean)) => Some(MetricValue::Boolean(boolean)),
+        (MetricType::Boolean, MetricValue::Text(text)) => text.trim().parse().ok().map(MetricValue::Boolean),
+        (MetricType::List, MetricValue::List(list)) => Some(MetricValue::List(list)),
+        (MetricType::Table, MetricValue::Table(table)) => Some(MetricValue::Table(table)),
+        _ => None
+    };
+
+    let coerced_value = coerced_value
+        .ok_or_else(|| format!("expects a {:?} value, found {}", metric_type, found_type).to_lowercase())?;
+
+    let numeric_value = match coerced_value {
+        MetricValue::Number(number) => Some(number as f64),
+        MetricValue::Float(float) => Some(float),
+        _ => None
+    };
+    if let Some(numeric_value) = numeric_value {
+
+        if output.minimum.is_some_and(|minimum| numeric_value < minimum) || output.maximum.is_some_and(|maximum| numeric_value > maximum) {
+            let minimum = output.minimum.map(|minimum| minimum.to_string()).unwrap_or("-inf".to_string());
+            let maximum = output.maximum.map(|maximum| maximum.to_string()).unwrap_or("+inf".to_string());
+            return Err(format!("value {} is outside of bounds [{}, {}]", numeric_value, minimum, maximum));
+        }
+    }
+
+    Ok(coerced_value)
+}
+
+/// Find undeclared metric keys and declared outputs missing from the metrics
+fn validate_metric_keys(metrics_file: &str, metrics: &HashMap<String, MetricValue>, outputs: &[FunctionOutput]) -> Vec<String> {
+
+    let mut undeclared_keys: Vec<&String> = metrics.keys()
+        .filter(|metric_key| !outputs.iter().any(|output| &output.key == *metric_key))
+        .collect();
+    undeclared_keys.sort();
+
+    let undeclared_problems = undeclared_keys.into_iter()
+        .map(|metric_key| format!("{}: undeclared output key \"{}\"", metrics_file, metric_key));
+    let missing_problems = outputs.iter()
+        .filter(|output| !metrics.contains_key(&output.key))
+        .map(|output| format!("{}: missing declared output key \"{}\"", metrics_file, output.key));
+
+    undeclared_problems.chain(missing_problems).collect()
+}
+
+/// Read issues from result/issues.toml (or issues.json and issues.ndjson), None is
+/// returned when the function did not write any issue file
+pub fn read_issue_file(result_reader: &ResultReader, problems: &mut Vec<String>) -> Option<Vec<CodeIssue>> {
+
+    let result_files = result_reader.list_entries();
+    let issues_file = format!("result/{}", select_result_file(&result_files, &ISSUES_FILES, problems)?);
+    let content = read_checked(result_reader, &issues_file, problems)?;
+
+    if issues_file.ends_with(".ndjson") {
+        return Some(parse_ndjson_issues(&issues_file, &content, problems));
+    }
+
+    let parsed_issues = if issues_file.ends_with(".json") {
+        serde_json::from_str::<IssueContainer>(&content).map_err(|err| describe_json_error(&issues_file, &err))
+    }
+    else {
+        toml::from_str::<IssueContainer>(&content).map_err(|err| describe_toml_error(&issues_file, &content, &err))
+    };
+
+    match parsed_issues {
+        Ok(issue_container) => Some(issue_container.issues),
+        Err(problem) => {
+            problems.push(problem);
+            Some(vec![])
+        }
+    }
+}
+
+/// Parse one issue per line, invalid lines are reported and skipped
+fn parse_ndjson_issues(issues_file: &str, content: &str, problems: &mut Vec<String>) -> Vec<CodeIssue> {
+
+    let mut issues: Vec<CodeIssue> = vec![];
+
+    for (line_index, line) in content.lines().enumerate() {
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<CodeIssue>(line) {
+            Ok(issue) => issues.push(issue),
+            Err(err) => problems.push(format!("{}:{}:{}: {}", issues_file, line_index + 1, err.column(), json_error_message(&err)))
+        }
+    }
+
+    issues
+}
+
+/// Pick the result file with the highest precedence, other formats are ignored
+/// and reported so that the function author notices it
+fn select_result_file<'a>(result_files: &[String], candidates: &[&'a str], problems: &mut Vec<String>) -> Option<&'a str> {
+
+    let present_files: Vec<&str> = candidates.iter()
+        .copied()
+        .filter(|candidate| result_files.iter().any(|result_file| result_file == candidate))
+        .collect();
+
+    if present_files.len() > 1 {
+        problems.push(format!("result/{}: also found {}, only one format is read", present_files[0], present_files[1..].join(", ")));
+    }
+
+    present_files.first().copied()
+}
+
+/// Describe a JSON error with its location ("result/data.json:3:7: ...")
+pub fn describe_json_error(file_name: &str, err: &serde_json::Error) -> String {
+    format!("{}:{}:{}: {}", file_name, err.line(), err.column(), json_error_message(err))
+}
+
+/// serde_json appends the location to its messages, it is removed to avoid duplicates
+fn json_error_message(err: &serde_json::Error) -> String {
+
+    let message = err.to_string();
+    let location_suffix = format!(" at line {} column {}", err.line(), err.column());
+
+    message.strip_suffix(&location_suffix).unwrap_or(&message).to_string()
+}
+
+/// Read the CycloneDX and SPDX documents written in the result directory
+pub fn read_sboms(result_reader: &ResultReader, problems: &mut Vec<String>) -> Vec<ScanComponents> {
+
+    let mut sboms: Vec<ScanComponents> = vec![];
+
+    let result_files = result_reader.list_files().into_iter()
+        .filter(|file_name| file_name.ends_with(".json"))
+        .filter(|file_name| !METRICS_FILES.contains(&file_name.as_str()) && !ISSUES_FILES.contains(&file_name.as_str()));
+
+    for file_name in result_files {
+
+        let sbom_file = format!("result/{}", file_name);
+        let content = match read_checked(result_reader, &sbom_file, problems) {
+            Some(content) => content,
+            None => continue
+        };
+
+        let format = match detect_sbom(&content) {
+            Some(format) => format,
+            None => continue
+        };
+
+        match parse_sbom(format, &content) {
+            Ok(components) => sboms.push(ScanComponents { file: file_name, format, components }),
+            Err(err) => problems.push(format!("{}: invalid {:?} document ({:#})", sbom_file, format, err))
+        }
+    }
+
+    sboms
+}
+
+/// Derive the component count and the license breakdown of the SBOMs
+pub fn sbom_metrics(sboms: &[ScanComponents]) -> Vec<ScanMetadata> {
+
+    if sboms.is_empty() {
+        return vec![];
+    }
+
+    let components: Vec<&SbomComponent> = sboms.iter().flat_map(|sbom| sbom.components.iter()).collect();
+
+    let mut license_counts: BTreeMap<String, MetricValue> = BTreeMap::new();
+    for component in components.iter() {
+
+        let license = match component.licenses.as_slice() {
+            [] => "unknown".to_string(),
+            licenses => licenses.join(" AND ")
+        };
+        let license_count = license_counts.entry(license).or_insert(MetricValue::Number(0));
+        if let MetricValue::Number(count) = license_count {
+            *count += 1;
+        }
+    }
+
+    vec![
+        ScanMetadata {
+            key: SBOM_COMPONENTS_KEY.to_string(),
+            description: "Components listed in the software bill of materials".to_string(),
+            value: MetricValue::Number(components.len() as i64),
+            unit: Some("components".to_string())
+        },
+        ScanMetadata {
+            key: SBOM_LICENSES_KEY.to_string(),
+            description: "Components per license in the software bill of materials".to_string(),
+            value: MetricValue::Table(license_counts),
+            unit: Some("components".to_string())
+        }
+    ]
+}
+
+/// Describe a TOML error with its location ("result/data.toml:3:7: ...")
+pub fn describe_toml_error(file_name: &str, content: &str, err: &toml::de::Error) -> String {
+
+    let message = err.message().trim();
+
+    match err.span() {
+        Some(span) => {
+            let (line, column) = find_line_column(content, span.start);
+            format!("{}:{}:{}: {}", file_name, line, column, message)
+        },
+        None => format!("{}: {}", file_name, message)
+    }
+}
+
+/// Convert a byte offset into a 1-based line and column
+fn find_line_column(content: &str, offset: usize) -> (usize, usize) {
+
+    let preceding_content = &content[..offset.min(content.len())];
+    let line = preceding_content.matches('\n').count() + 1;
+    let line_start = preceding_content.rfind('\n').map(|position| position + 1).unwrap_or(0);
+
+    (line, preceding_content[line_start..].chars().count() + 1)
+}
+
+/// Append validation problems to the scan logs, the scan fails on the first
+/// problem unless the function only asks for warnings
+pub fn report_problems(scan: &mut Scan, code_function: &CodeFunction, problems: &[String]) {
+
+    if problems.is_empty() {
+        return;
+    }
+
+    for problem in problems {
+        warn!("Function \"{}\" result validation: {}", code_function.name, problem);
+        scan.logs.push_str(&format!("[chicon] Result validation: {}\n", problem));
+    }
+
+    let is_first_failure = scan.failure_reason.is_none() && !scan.is_cancelled;
+    if code_function.result_validation == ResultValidation::Fail && is_first_failure {
+
+        scan.has_failed = true;
+        scan.failure_reason = Some(FailureReason::ResultValidationError);
+        scan.error_message = Some(match problems.len() {
+            1 => problems[0].to_string(),
+            problem_count => format!("{} (and {} other result problems)", problems[0], problem_count - 1)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use anyhow::Error;
+    use crate::components::config::ConfigWorkspace;
+    use crate::models::{FunctionOutput, MetricType, MetricValue, SbomComponent, ScanComponents};
+    use crate::parsers::sbom::SbomFormat;
+    use crate::test_utils::TempDir;
+    use super::{coerce_metric, read_issue_file, sbom_metrics, ResultReader, describe_toml_error, parse_ndjson_issues, select_result_file, validate_metric_keys, ISSUES_FILES};
+
+    #[test]
+    fn should_locate_toml_errors() {
+
+        let content = "lines = 120\nlanguage = \"rust\nfiles = 3\n";
+        let err = toml::from_str::<HashMap<String, MetricValue>>(content).expect_err("Expected a TOML error");
+
+        let description = describe_toml_error("result/data.toml", content, &err);
+
+        assert!(description.starts_with("result/data.toml:2:"), "unexpected description: {}", description);
+    }
+
+    #[test]
+    fn should_validate_metric_keys() {
+
+        let outputs = vec![
+            FunctionOutput { key: "lines".into(), description: "Lines of code".into(), ..Default::default() },
+            FunctionOutput { key: "files".into(), description: "Number of files".into(), ..Default::default() }
+        ];
+        let metrics = HashMap::from([
+            ("lines".to_string(), MetricValue::Number(120)),
+            ("language".to_string(), MetricValue::Text("rust".into()))
+        ]);
+
+        let problems = validate_metric_keys("result/data.toml", &metrics, &outputs);
+
+        assert_eq!(problems, vec![
+            "result/data.toml: undeclared output key \"language\"".to_string(),
+            "result/data.toml: missing declared output key \"files\"".to_string()
+        ]);
+    }
+
+    #[test]
+    fn should_parse_typed_metrics() {
+
+        let content = "coverage = 87.5\nlines = 120\nlanguages = [\"rust\", \"python\"]\n[per_language]\nrust = 100\n";
+        let metrics: HashMap<String, MetricValue> = toml::from_str(content).expect("Expected valid metrics");
+
+        assert_eq!(metrics["coverage"], MetricValue::Float(87.5));
+        assert_eq!(metrics["lines"], MetricValue::Number(120));
+        assert_eq!(metrics["languages"].type_name(), "list");
+        assert_eq!(metrics["per_language"].type_name(), "table");
+    }
+
+    #[test]
+    fn should_coerce_metrics() {
+
+        let coverage = FunctionOutput {
+            key: "coverage".into(),
+            metric_type: Some(MetricType::Float),
+            unit: Some("%".into()),
+            minimum: Some(0.0),
+            maximum: Some(100.0),
+            ..Default::default()
+        };
+
+        assert_eq!(coerce_metric(MetricValue::Number(87), &coverage), Ok(MetricValue::Float(87.0)));
+        assert_eq!(coerce_metric(MetricValue::Text("87.5".into()), &coverage), Ok(MetricValue::Float(87.5)));
+        assert_eq!(coerce_metric(MetricValue::Boolean(true), &coverage), Err("expects a float value, found boolean".into()));
+        assert_eq!(coerce_metric(MetricValue::Float(120.0), &coverage), Err("value 120 is outside of bounds [0, 100]".into()));
+    }
+
+    #[test]
+    fn should_select_result_file_by_precedence() {
+
+        let result_files = vec!["issues.json".to_string(), "issues.ndjson".to_string(), "report.sarif".to_string()];
+        let mut problems: Vec<String> = vec![];
+
+        assert_eq!(select_result_file(&result_files, &ISSUES_FILES, &mut problems), Some("issues.json"));
+        assert_eq!(select_result_file(&result_files[2..], &ISSUES_FILES, &mut problems), None);
+        assert_eq!(problems, vec!["result/issues.json: also found issues.ndjson, only one format is read".to_string()]);
+    }
+
+    #[test]
+    fn should_parse_ndjson_issues() {
+
+        let content = "{\"name\": \"Hardcoded password\", \"severity\": \"high\"}\n\n{\"severity\": \"low\"}\n{\"name\": \"Weak hash\", \"startLine\": 4}\n";
+        let mut problems: Vec<String> = vec![];
+
+        let issues = parse_ndjson_issues("result/issues.ndjson", content, &mut problems);
+
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[1].start_line, Some(4));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("result/issues.ndjson:3:"), "unexpected problem: {}", problems[0]);
+    }
+
+    #[test]
+    fn should_derive_sbom_metrics() {
+
+        let build_component = |name: &str, licenses: &[&str]| SbomComponent {
+            name: name.into(),
+            version: None,
+            purl: None,
+            component_type: Some("library".into()),
+            licenses: licenses.iter().map(|license| license.to_string()).collect()
+        };
+        let sboms = vec![ScanComponents {
+            file: "sbom.cdx.json".into(),
+            format: SbomFormat::CycloneDx,
+            components: vec![build_component("serde", &["MIT"]), build_component("log", &["MIT"]), build_component("internal", &[])]
+        }];
+
+        let metrics = sbom_metrics(&sboms);
+
+        assert_eq!(metrics[0].value, MetricValue::Number(3));
+        match &metrics[1].value {
+            MetricValue::Table(license_counts) => {
+                assert_eq!(license_counts["MIT"], MetricValue::Number(2));
+                assert_eq!(license_counts["unknown"], MetricValue::Number(1));
+            },
+            _ => panic!("Expected a license table")
+        }
+        assert!(sbom_metrics(&[]).is_empty());
+    }
+
+    #[test]
+    fn should_refuse_untrusted_result_files() -> Result<(), Error> {
+
+        let result_dir = TempDir::new("results")?;
+        let result_path = result_dir.path();
+        fs::write(result_path.join("data.toml"), "lines = 120")?;
+        fs::write(result_path.join("large.json"), "x".repeat(1_000_001))?;
+        fs::write(result_path.join("binary.json"), [0x7b, 0xff, 0xfe])?;
+        symlink("/etc/hostname", result_path.join("issues.toml"))?;
+
+        let config = ConfigWorkspace { result_file_limit: 1, ..Default::default() };
+        let result_reader = ResultReader::new(result_path.to_path_buf(), &config);
+
+        let listed_files = result_reader.list_files();
+        let data_content = result_reader.read_file("data.toml")?;
+        let missing_content = result_reader.read_file("issues.json")?;
+        let link_error = result_reader.read_file("issues.toml").map(|_| ()).unwrap_err();
+        let size_error = result_reader.read_file("large.json").map(|_| ()).unwrap_err();
+        let encoding_error = result_reader.read_file("binary.json").map(|_| ()).unwrap_err();
+        let escape_error = result_reader.read_file("../data.toml").map(|_| ()).unwrap_err();
+
+        assert_eq!(listed_files, vec!["binary.json".to_string(), "data.toml".to_string(), "large.json".to_string()]);
+        assert_eq!(data_content.as_deref(), Some("lines = 120"));
+        assert_eq!(missing_content, None);
+        assert_eq!(link_error.to_string(), "symbolic links are not accepted");
+        assert!(size_error.to_string().contains("1Mb file limit"));
+        assert_eq!(encoding_error.to_string(), "invalid UTF-8 content at byte 1");
+        assert!(escape_error.to_string().contains("relative to the result directory"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_report_linked_issue_file() -> Result<(), Error> {
+
+        let result_dir = TempDir::new("results")?;
+        symlink("/etc/hostname", result_dir.path().join("issues.json"))?;
+
+        let result_reader = ResultReader::new(result_dir.path().to_path_buf(), &ConfigWorkspace::default());
+        let mut problems: Vec<String> = vec![];
+
+        assert!(read_issue_file(&result_reader, &mut problems).is_none());
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("result/issues.json"), "unexpected problem: {}", problems[0]);
+        assert!(problems[0].contains("symbolic links are not accepted"), "unexpected problem: {}", problems[0]);
+
+        Ok(())
+    }
+
+}
@@ -4,18 +4,23 @@ use std::net::TcpStream;
 use std::thread;
 use std::process;
 
-use anyhow::Error;
+use anyhow::{anyhow, bail, Error};
 use isahc::{prelude::*, Request};
 use log::error;
 use log::info;
-use url::Url;
-use tungstenite::{connect, WebSocket, stream::MaybeTlsStream};
+use log::warn;
+use serde::Deserialize;
+use serde_json::json;
+use url::{form_urlencoded, Url};
+use tungstenite::{client, HandshakeError, WebSocket, stream::MaybeTlsStream};
 
-use crate::models::{CodeFunction, Repository, Scan, CodeIssue, GenericModel, MassIssues};
-use super::config::Config;
+use crate::models::{CodeFunction, Repository, Scan, ScanComponents, CodeIssue, GenericModel, MassIssues};
+use super::{artifacts::Artifact, config::{Config, ProtocolVersion}};
 
 pub type Ws = WebSocket<MaybeTlsStream<TcpStream>>;
 
+const MIN_UPLOAD_RATE: u64 = 256_000;   // Bytes per second assumed for uploads on slow links
+
 pub struct Scheduler {
 
     base_url: String,
@@ -51,6 +56,21 @@ impl Scheduler {
         Ok(code_functions)
     }
 
+    /// Every repository visible to the runner, used for wildcard scan requests
+    pub fn get_repositories(&self) -> Result<Vec<Repository>, Error> {
+
+        let repositories_url = format!("{}/repositories", self.base_url);
+        let repositories = Request::get(&repositories_url)
+            .header("Content-Type", "application/json")
+            .header("Authorization", self.authorization_value())
+            .timeout(self.default_duration)
+            .body(())?
+            .send()?
+            .json::<Vec<Repository>>()?;
+
+        Ok(repositories)
+    }
+
     pub fn get_functions(&self, functions: &[String]) -> Result<Vec<CodeFunction>, Error> {
 
         let functions_url = format!("{}/functions", self.base_url);
@@ -112,39 +132,126 @@ impl Scheduler {
         Ok(())
     }
 
+    /// Submit the normalized components of an SBOM linked to a scan
+    pub fn store_components(&self, scan_id: &str, scan_components: &ScanComponents) -> Result<(), Error> {
+
+        let components_url = format!("{}/scans/{}/components", self.base_url, scan_id);
+        let request_body = serde_json::to_string(scan_components)?;
+
+        let components_response = Request::post(&components_url)
+            .header("Content-Type", "application/json")
+            .header("Authorization", self.authorization_value())
+            .timeout(self.default_duration)
+            .body(request_body)?
+            .send()?;
+
+        if !components_response.status().is_success() {
+            bail!("Scheduler rejected components of {} with status {}", scan_components.file, components_response.status());
+        }
+
+        Ok(())
+    }
+
+    /// Upload a compressed artifact linked to a scan
+    pub fn store_artifact(&self, scan_id: &str, artifact: &Artifact) -> Result<(), Error> {
+
+        let encoded_path: String = form_urlencoded::byte_serialize(artifact.path.as_bytes()).collect();
+        let artifact_url = format!("{}/scans/{}/artifacts?path={}", self.base_url, scan_id, encoded_path);
+
+        let artifact_response = Request::post(&artifact_url)
+            .header("Content-Type", artifact.content_type)
+            .header("Content-Encoding", "gzip")
+            .header("Authorization", self.authorization_value())
+            .timeout(upload_duration(self.default_duration, artifact.compressed_content.len()))
+            .body(artifact.compressed_content.clone())?
+            .send()?;
+
+        if !artifact_response.status().is_success() {
+            bail!("Scheduler rejected artifact {} with status {}", artifact.path, artifact_response.status());
+        }
+
+        Ok(())
+    }
+
     fn authorization_value(&self) -> String {
         format!("Bearer {}", self.config.scheduler.token)
     }
 
 }
 
-/// Perform an authentication process with the scheduler
-pub fn authenticate_runner(shared_config: Rc<Config>, websocket: &mut Ws) {
+/// Request timeout for an upload, the default timeout is extended based on the body size
+fn upload_duration(default_duration: Duration, body_size: usize) -> Duration {
+    default_duration + Duration::from_secs(body_size as u64 / MIN_UPLOAD_RATE)
+}
+
+/// Perform an authentication process with the scheduler, the scan protocol
+/// version is negotiated at the same time (none when the scheduler refused)
+pub fn authenticate_runner(shared_config: Rc<Config>, websocket: &mut Ws, offered_protocol: ProtocolVersion) -> Option<ProtocolVersion> {
 
-    websocket.send(tungstenite::Message::Text(shared_config.scheduler.token.to_string())).unwrap_or_else(|err| {
+    let auth_request = encode_auth_request(&shared_config.scheduler.token, offered_protocol);
+
+    websocket.send(tungstenite::Message::Text(auth_request)).unwrap_or_else(|err| {
         error!("Could not send authentication request, check the network connection ({})", err);
         process::exit(1);
     });
-    let auth_response = websocket.read().unwrap_or_else(|err| {
-        error!("Could not receive authentication response, check the network connection ({})", err);
-        process::exit(1);
-    });
-
-    match auth_response.to_text() {
-        Ok("auth-ok") => {
-            info!("Authentication done, the runner is ready to perform scans");
-        }
-        _ => {
-            error!("Authentication failed, check the runner token");
-            process::exit(1);
+    let auth_response = match websocket.read() {
+        Ok(auth_response) => auth_response,
+        Err(err) => {
+            warn!("Could not receive authentication response ({})", err);
+            return None;
         }
+    };
+
+    let protocol = auth_response.to_text().ok()
+        .and_then(|response| decode_auth_response(response, offered_protocol))?;
+    info!("Authentication done with protocol {:?}, the runner is ready to perform scans", protocol);
+
+    Some(protocol)
+}
+
+/// The v1 authentication request is the bare token, later versions send
+/// a JSON frame listing the protocols supported by the runner
+fn encode_auth_request(token: &str, offered_protocol: ProtocolVersion) -> String {
+
+    match offered_protocol {
+        ProtocolVersion::V1 => token.to_string(),
+        ProtocolVersion::V2 => json!({
+            "type": "auth",
+            "token": token,
+            "versions": ["v2", "v1"]
+        }).to_string()
     }
 }
 
-/// Try to perform a websocket connection with the scheduler
-pub fn try_scheduler_ws_connection(shared_config: Rc<Config>, websocket_url: &Url) -> Ws {
+/// A bare "auth-ok" keeps the v1 protocol, a JSON response selects one of the offered versions
+fn decode_auth_response(response: &str, offered_protocol: ProtocolVersion) -> Option<ProtocolVersion> {
+
+    if response == "auth-ok" {
+        return Some(ProtocolVersion::V1);
+    }
+    if offered_protocol == ProtocolVersion::V1 {
+        return None;
+    }
+
+    let auth_response: AuthResponse = serde_json::from_str(response).ok()?;
+    if auth_response.response_type != "auth-ok" {
+        return None;
+    }
+    Some(auth_response.version)
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+
+    #[serde(rename = "type")]
+    response_type: String,
+
+    version: ProtocolVersion
+
+}
 
-    let mut some_websocket: Option<Ws> = None;
+/// Try to perform a websocket connection with the scheduler
+pub fn try_scheduler_ws_connection(shared_config: Rc<Config>, websocket_url: &Url) -> (Ws, TcpStream) {
 
     let mut retry_period = shared_config.scheduler.retry_period;
     let retry_scale_factor = shared_config.scheduler.retry_scale_factor;
@@ -152,18 +259,11 @@ pub fn try_scheduler_ws_connection(shared_config: Rc<Config>, websocket_url: &Ur
     
     loop {
 
-        let mut is_connected = false;
-
-        match connect(websocket_url.to_string()) {
-            Ok((socket, _response)) => {
-
-                some_websocket = Some(socket);
-                is_connected = true;
-
-            },
+        match open_websocket(websocket_url) {
+            Ok(connection) => return connection,
             Err(err) => {
 
-                error!("Scheduler connection failed: {}", err);
+                error!("Scheduler connection failed: {:#}", err);
                 error!("Retry in {} seconds", retry_period);
 
                 let duration = Duration::from_secs(retry_period);
@@ -175,13 +275,59 @@ pub fn try_scheduler_ws_connection(shared_config: Rc<Config>, websocket_url: &Ur
 
             }
         };
+    }
+}
 
-        if is_connected && some_websocket.is_some() {
-            return some_websocket.unwrap_or_else(|| {
-                error!("Expected a valid websocket, internal logic error");
-                process::exit(1);
-            });
-        }
+/// Open a websocket and keep a handle on its TCP socket, socket options such
+/// as read timeouts set through the handle also apply below a TLS layer
+fn open_websocket(websocket_url: &Url) -> Result<(Ws, TcpStream), Error> {
+
+    let addresses = websocket_url.socket_addrs(|| Some(80))?;
+    let stream = TcpStream::connect(addresses.as_slice())?;
+    stream.set_nodelay(true)?;
+    let tcp_stream = stream.try_clone()?;
+
+    let (websocket, _response) = client(websocket_url.as_str(), MaybeTlsStream::Plain(stream)).map_err(|err| match err {
+        HandshakeError::Failure(failure) => Error::new(failure).context("Websocket handshake failed"),
+        HandshakeError::Interrupted(_) => anyhow!("Websocket handshake interrupted")
+    })?;
+
+    Ok((websocket, tcp_stream))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::time::Duration;
+
+    use crate::components::config::ProtocolVersion;
+    use super::{decode_auth_response, encode_auth_request, upload_duration};
 
+    #[test]
+    fn should_keep_bare_token_for_v1() {
+
+        assert_eq!("secret", encode_auth_request("secret", ProtocolVersion::V1));
+        assert!(encode_auth_request("secret", ProtocolVersion::V2).contains("\"versions\":[\"v2\",\"v1\"]"));
     }
+
+    #[test]
+    fn should_negotiate_protocol_version() {
+
+        assert_eq!(Some(ProtocolVersion::V1), decode_auth_response("auth-ok", ProtocolVersion::V2));
+        assert_eq!(Some(ProtocolVersion::V2), decode_auth_response(r#"{"type":"auth-ok","version":"v2"}"#, ProtocolVersion::V2));
+        assert_eq!(None, decode_auth_response(r#"{"type":"auth-ok","version":"v2"}"#, ProtocolVersion::V1));
+        assert_eq!(None, decode_auth_response(r#"{"type":"auth-failed","version":"v2"}"#, ProtocolVersion::V2));
+        assert_eq!(None, decode_auth_response(r#"{"type":"auth-ok","version":"v3"}"#, ProtocolVersion::V2));
+        assert_eq!(None, decode_auth_response("auth-ko", ProtocolVersion::V2));
+    }
+
+    #[test]
+    fn should_extend_upload_duration() {
+
+        let default_duration = Duration::from_secs(10);
+
+        assert_eq!(upload_duration(default_duration, 4_096), default_duration);
+        assert_eq!(upload_duration(default_duration, 10_000_000), Duration::from_secs(49));
+    }
+
 }
@@ -0,0 +1,213 @@
+use anyhow::{bail, Error, Result};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use toml::value::Datetime;
+
+use crate::models::CodeIssue;
+
+pub const REPOSITORY_CONFIG_FILE: &str = ".chicon.toml";
+const INLINE_MARKER: &str = "chicon-ignore";
+const INLINE_JUSTIFICATION: &str = "Inline chicon-ignore comment";
+
+#[derive(Deserialize)]
+struct SuppressionFile {
+
+    #[serde(default)]
+    suppressions: Vec<SuppressionEntry>
+
+}
+
+#[derive(Deserialize)]
+struct SuppressionEntry {
+
+    rule: Option<String>,
+
+    path: Option<String>,   // Glob relative to the repository root
+
+    fingerprint: Option<String>,
+
+    justification: Option<String>,
+
+    expires: Option<Datetime>
+
+}
+
+/// Valid and unexpired suppression, all declared criteria should match an issue
+struct Suppression {
+
+    rule: Option<String>,
+
+    path: Option<Pattern>,
+
+    fingerprint: Option<String>,
+
+    justification: String
+
+}
+
+/// Suppressions declared by a repository in its .chicon.toml file
+#[derive(Default)]
+pub struct Suppressions {
+
+    entries: Vec<Suppression>
+
+}
+
+impl Suppressions {
+
+    /// Parse suppressions, invalid and expired entries are skipped and described in the messages
+    pub fn parse(content: &str, today: (i64, u32, u32)) -> Result<(Suppressions, Vec<String>), Error> {
+
+        let suppression_file: SuppressionFile = toml::from_str(content)?;
+        let mut entries: Vec<Suppression> = vec![];
+        let mut messages: Vec<String> = vec![];
+
+        for (entry_index, entry) in suppression_file.suppressions.into_iter().enumerate() {
+
+            match validate_entry(entry, today) {
+                Ok(suppression) => entries.push(suppression),
+                Err(err) => messages.push(format!("{}: suppression #{} ignored, {}", REPOSITORY_CONFIG_FILE, entry_index + 1, err))
+            }
+        }
+
+        Ok((Suppressions { entries }, messages))
+    }
+
+    /// Flag an issue matching a suppression or an inline comment on its reported line
+    pub fn apply(&self, issue: &mut CodeIssue, source_line: Option<&str>) {
+
+        let rule = issue.rule_id.as_deref().unwrap_or(&issue.name);
+        let match_options = MatchOptions { require_literal_separator: true, ..Default::default() };
+
+        let potential_suppression = self.entries.iter().find(|suppression| {
+
+            let is_rule_matching = suppression.rule.as_ref().map_or(true, |suppression_rule| suppression_rule == rule);
+            let is_path_matching = suppression.path.as_ref().map_or(true, |path_pattern| {
+                issue.path.as_ref().is_some_and(|issue_path| path_pattern.matches_with(issue_path, match_options))
+            });
+            let is_fingerprint_matching = suppression.fingerprint.as_ref().map_or(true, |fingerprint| issue.fingerprint.as_ref() == Some(fingerprint));
+
+            is_rule_matching && is_path_matching && is_fingerprint_matching
+        });
+
+        let justification = match potential_suppression {
+            Some(suppression) => Some(suppression.justification.to_string()),
+            None if source_line.is_some_and(|source_line| has_inline_ignore(source_line, rule)) => Some(INLINE_JUSTIFICATION.to_string()),
+            None => None
+        };
+
+        if let Some(justification) = justification {
+            issue.is_suppressed = true;
+            issue.suppression_justification = Some(justification);
+        }
+    }
+
+}
+
+fn validate_entry(entry: SuppressionEntry, today: (i64, u32, u32)) -> Result<Suppression, Error> {
+
+    if entry.rule.is_none() && entry.path.is_none() && entry.fingerprint.is_none() {
+        bail!("a rule, a path or a fingerprint is required");
+    }
+
+    let justification = match entry.justification.filter(|justification| !justification.trim().is_empty()) {
+        Some(justification) => justification,
+        None => bail!("a justification is required")
+    };
+
+    let expiry_date = match entry.expires.and_then(|expires| expires.date) {
+        Some(expiry_date) => expiry_date,
+        None => bail!("an expiry date is required (such as expires = 2025-12-31)")
+    };
+    if (expiry_date.year as i64, expiry_date.month as u32, expiry_date.day as u32) < today {
+        bail!("expired on {}", expiry_date);
+    }
+
+    let path = match entry.path {
+        Some(raw_path) => Some(Pattern::new(&raw_path).map_err(|err| anyhow::anyhow!("invalid path pattern ({})", err))?),
+        None => None
+    };
+
+    Ok(Suppression {
+        rule: entry.rule,
+        path,
+        fingerprint: entry.fingerprint,
+        justification
+    })
+}
+
+/// Inline comments either ignore all rules ("chicon-ignore") or a list of rules
+/// ("chicon-ignore[rule-a,rule-b]")
+fn has_inline_ignore(source_line: &str, rule: &str) -> bool {
+
+    let marker_position = match source_line.find(INLINE_MARKER) {
+        Some(marker_position) => marker_position,
+        None => return false
+    };
+
+    let marker_suffix = &source_line[marker_position + INLINE_MARKER.len()..];
+    match marker_suffix.strip_prefix('[').and_then(|rule_list| rule_list.split_once(']')) {
+        Some((rule_list, _)) => rule_list.split(',').any(|ignored_rule| ignored_rule.trim() == rule),
+        None => true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use crate::models::CodeIssue;
+    use crate::test_utils::build_issue;
+    use super::{has_inline_ignore, Suppressions};
+
+    const TODAY: (i64, u32, u32) = (2025, 6, 1);
+
+    #[test]
+    fn should_flag_suppressed_issues() -> Result<(), Error> {
+
+        let content = r#"
+            [[suppressions]]
+            rule = "python.lang.security.eval"
+            path = "tests/**"
+            justification = "Test fixtures evaluate known payloads"
+            expires = 2025-12-31
+
+            [[suppressions]]
+            fingerprint = "f3a1"
+            justification = "Accepted risk"
+            expires = 2025-01-31
+
+            [[suppressions]]
+            rule = "hardcoded-password"
+            expires = 2025-12-31
+        "#;
+
+        let (suppressions, messages) = Suppressions::parse(content, TODAY)?;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].contains("expired on 2025-01-31"));
+        assert!(messages[1].contains("justification is required"));
+
+        let mut test_issue = CodeIssue { path: Some("tests/unit/test_eval.py".into()), ..build_issue("python.lang.security.eval", "f3a1") };
+        suppressions.apply(&mut test_issue, None);
+        assert!(test_issue.is_suppressed);
+        assert_eq!(test_issue.suppression_justification.as_deref(), Some("Test fixtures evaluate known payloads"));
+
+        let mut source_issue = CodeIssue { path: Some("src/eval.py".into()), ..build_issue("python.lang.security.eval", "f3a1") };
+        suppressions.apply(&mut source_issue, Some("eval(payload)"));
+        assert!(!source_issue.is_suppressed);
+
+        suppressions.apply(&mut source_issue, Some("eval(payload)  # chicon-ignore"));
+        assert!(source_issue.is_suppressed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_match_inline_rules() {
+
+        assert!(has_inline_ignore("password = \"x\"  # chicon-ignore[hardcoded-password, weak-hash]", "hardcoded-password"));
+        assert!(!has_inline_ignore("password = \"x\"  # chicon-ignore[weak-hash]", "hardcoded-password"));
+        assert!(!has_inline_ignore("password = \"x\"", "hardcoded-password"));
+    }
+
+}
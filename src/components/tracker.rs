@@ -0,0 +1,516 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Error, Result};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::models::ResourceUsage;
+use crate::utils::current_timestamp;
+use super::config::ConfigContainer;
+
+pub const RUNNER_LABEL: &str = "chicon.runner";
+pub const SCAN_LABEL: &str = "chicon.scan";
+pub const FUNCTION_LABEL: &str = "chicon.function";
+pub const STAGE_LABEL: &str = "chicon.stage";
+
+/// Final state of a stage container
+#[derive(Deserialize, Default)]
+pub struct ContainerState {
+
+    #[serde(rename = "OOMKilled", default)]
+    pub oom_killed: bool
+
+}
+
+/// Container listed by nerdctl in the containerd namespace
+#[derive(Deserialize)]
+struct ListedContainer {
+
+    #[serde(rename = "ID")]
+    id: String,
+
+    #[serde(rename = "Labels", default)]
+    labels: String
+
+}
+
+#[derive(Default)]
+struct CancelState {
+
+    active: HashMap<String, Option<String>>,   // Repository IDs with the scan request being processed
+
+    requested: HashSet<String>,     // Repository IDs whose scan must stop
+
+    queued: HashSet<String>,    // Scan requests waiting in the control channel
+
+    running: HashSet<String>,   // Scan requests being processed
+
+    cancelled: HashSet<String>  // Scan requests that must not scan any further repository
+
+}
+
+/// Cancellation requests for repositories being scanned and for scan requests,
+/// shared between the scheduler message reader and the container execution
+#[derive(Clone, Default)]
+pub struct CancelSignal {
+
+    state: Arc<Mutex<CancelState>>
+
+}
+
+impl CancelSignal {
+
+    /// Register a scan request before it is handed to the control channel
+    pub fn queue(&self, request_id: &str) {
+
+        if let Ok(mut state) = self.state.lock() {
+            state.queued.insert(request_id.to_string());
+        }
+    }
+
+    pub fn start(&self, request_id: &str) {
+
+        if let Ok(mut state) = self.state.lock() {
+            state.queued.remove(request_id);
+            state.running.insert(request_id.to_string());
+        }
+    }
+
+    /// Forget a scan request once processed or dropped from the queue
+    pub fn finish(&self, request_id: &str) {
+
+        if let Ok(mut state) = self.state.lock() {
+            state.queued.remove(request_id);
+            state.running.remove(request_id);
+            state.cancelled.remove(request_id);
+        }
+    }
+
+    pub fn begin(&self, repository_id: &str, request_id: Option<&str>) {
+
+        if let Ok(mut state) = self.state.lock() {
+            state.active.insert(repository_id.to_string(), request_id.map(|request_id| request_id.to_string()));
+            state.requested.remove(repository_id);
+
+            // The request may have been cancelled since the last check
+            if request_id.is_some_and(|request_id| state.cancelled.contains(request_id)) {
+                state.requested.insert(repository_id.to_string());
+            }
+        }
+    }
+
+    pub fn end(&self, repository_id: &str) {
+
+        if let Ok(mut state) = self.state.lock() {
+            state.active.remove(repository_id);
+            state.requested.remove(repository_id);
+        }
+    }
+
+    /// Request a cancellation, returns false when no scan is active for the repository
+    pub fn request(&self, repository_id: &str) -> bool {
+
+        match self.state.lock() {
+            Ok(mut state) if state.active.contains_key(repository_id) => {
+                state.requested.insert(repository_id.to_string());
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// Cancel a queued or running scan request, the repository being scanned
+    /// is stopped and the remaining repositories are skipped. Returns false
+    /// when the scan request is unknown or already processed
+    pub fn cancel_request(&self, request_id: &str) -> bool {
+
+        let Ok(mut state) = self.state.lock() else {
+            return false;
+        };
+        if !state.queued.contains(request_id) && !state.running.contains(request_id) {
+            return false;
+        }
+
+        state.cancelled.insert(request_id.to_string());
+        let repository_ids: Vec<String> = state.active.iter()
+            .filter(|(_, active_request_id)| active_request_id.as_deref() == Some(request_id))
+            .map(|(repository_id, _)| repository_id.clone())
+            .collect();
+        state.requested.extend(repository_ids);
+
+        true
+    }
+
+    pub fn is_requested(&self, repository_id: &str) -> bool {
+
+        self.state.lock()
+            .map(|state| state.requested.contains(repository_id))
+            .unwrap_or(false)
+    }
+
+    pub fn is_cancelled(&self, request_id: &str) -> bool {
+
+        self.state.lock()
+            .map(|state| state.cancelled.contains(request_id))
+            .unwrap_or(false)
+    }
+
+}
+
+/// Keeps track of the scans handled by the runner, containers are labeled
+/// with their scan key so that leftovers from a crash can be removed
+pub struct ContainerTracker {
+
+    runner_id: String,
+
+    namespace: String,
+
+    active_scans: Arc<Mutex<HashSet<String>>>,
+
+    scan_counter: AtomicU64,
+
+    cancel_signal: CancelSignal,
+
+    usage_totals: RefCell<HashMap<String, ResourceUsage>>   // Runner-wide usage per function
+
+}
+
+/// Scan registration, the scan is considered inactive once dropped
+pub struct ActiveScan {
+
+    pub key: String,
+
+    active_scans: Arc<Mutex<HashSet<String>>>
+
+}
+
+impl Drop for ActiveScan {
+
+    fn drop(&mut self) {
+
+        if let Ok(mut active_scans) = self.active_scans.lock() {
+            active_scans.remove(&self.key);
+        }
+    }
+
+}
+
+impl ContainerTracker {
+
+    pub fn new(config: &ConfigContainer, runner_id: &str) -> ContainerTracker {
+
+        ContainerTracker {
+            runner_id: sanitize_name(runner_id),
+            namespace: config.namespace.to_string(),
+            active_scans: Arc::new(Mutex::new(HashSet::new())),
+            scan_counter: AtomicU64::new(0),
+            cancel_signal: CancelSignal::default(),
+            usage_totals: RefCell::new(HashMap::new())
+        }
+    }
+
+    pub fn start_scan(&self) -> ActiveScan {
+
+        let scan_count = self.scan_counter.fetch_add(1, Ordering::Relaxed);
+        let key = format!("{:x}{:04x}", current_timestamp(), scan_count % 0x10000);
+
+        if let Ok(mut active_scans) = self.active_scans.lock() {
+            active_scans.insert(key.to_string());
+        }
+
+        ActiveScan {
+            key,
+            active_scans: self.active_scans.clone()
+        }
+    }
+
+    pub fn cancel_signal(&self) -> &CancelSignal {
+        &self.cancel_signal
+    }
+
+    /// Add a function usage to the runner-wide totals, the new total is returned
+    pub fn record_usage(&self, function_id: &str, usage: &ResourceUsage) -> ResourceUsage {
+
+        let mut usage_totals = self.usage_totals.borrow_mut();
+        let function_total = usage_totals.entry(function_id.to_string()).or_default();
+        function_total.add(usage);
+
+        function_total.clone()
+    }
+
+    pub fn container_name(&self, scan_key: &str, function_id: &str, stage_index: usize) -> String {
+        format!("chicon-{}-{}-{}-{}", self.runner_id, scan_key, sanitize_name(function_id), stage_index)
+    }
+
+    /// Name and labels flags for a stage container
+    pub fn container_args(&self, scan_key: &str, function_id: &str, stage_index: usize) -> Vec<String> {
+
+        vec![
+            "--name".into(),
+            self.container_name(scan_key, function_id, stage_index),
+            "--label".into(),
+            format!("{}={}", RUNNER_LABEL, self.runner_id),
+            "--label".into(),
+            format!("{}={}", SCAN_LABEL, scan_key),
+            "--label".into(),
+            format!("{}={}", FUNCTION_LABEL, function_id),
+            "--label".into(),
+            format!("{}={}", STAGE_LABEL, stage_index)
+        ]
+    }
+
+    pub fn inspect_pid(&self, container_name: &str) -> Option<u32> {
+
+        let output = Command::new("nerdctl")
+            .arg(format!("--namespace={}", self.namespace))
+            .arg("inspect")
+            .arg("--format")
+            .arg("{{.State.Pid}}")
+            .arg(container_name)
+            .output()
+            .ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+            .filter(|pid| *pid > 0)
+    }
+
+    /// Single stats line of a running container, used when its cgroup cannot be read
+    pub fn container_stats(&self, container_name: &str) -> Option<String> {
+
+        let output = Command::new("nerdctl")
+            .arg(format!("--namespace={}", self.namespace))
+            .arg("stats")
+            .arg("--no-stream")
+            .arg("--format")
+            .arg("{{json .}}")
+            .arg(container_name)
+            .output()
+            .ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        Some(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    pub fn kill_container(&self, container_name: &str) -> Result<(), Error> {
+
+        let output = Command::new("nerdctl")
+            .arg(format!("--namespace={}", self.namespace))
+            .arg("kill")
+            .arg(container_name)
+            .output()?;
+
+        if !output.status.success() {
+            let stderr_logs = String::from_utf8_lossy(&output.stderr);
+            bail!("Could not kill container {} ({})", container_name, stderr_logs.trim());
+        }
+
+        Ok(())
+    }
+
+    pub fn inspect_state(&self, container_name: &str) -> Result<ContainerState, Error> {
+
+        let output = Command::new("nerdctl")
+            .arg(format!("--namespace={}", self.namespace))
+            .arg("inspect")
+            .arg("--format")
+            .arg("{{json .State}}")
+            .arg(container_name)
+            .output()?;
+
+        if !output.status.success() {
+            let stderr_logs = String::from_utf8_lossy(&output.stderr);
+            bail!("Could not inspect container {} ({})", container_name, stderr_logs.trim());
+        }
+
+        let state: ContainerState = serde_json::from_slice(&output.stdout)?;
+        Ok(state)
+    }
+
+    pub fn remove_container(&self, container_name: &str) -> Result<(), Error> {
+
+        let output = Command::new("nerdctl")
+            .arg(format!("--namespace={}", self.namespace))
+            .arg("rm")
+            .arg("--force")
+            .arg(container_name)
+            .output()?;
+
+        if !output.status.success() {
+            let stderr_logs = String::from_utf8_lossy(&output.stderr);
+            bail!("Could not remove container {} ({})", container_name, stderr_logs.trim());
+        }
+
+        Ok(())
+    }
+
+    pub fn cleanup_orphans(&self) -> Result<usize, Error> {
+        remove_orphans(&self.namespace, &self.runner_id, &self.active_scans)
+    }
+
+    /// Periodically remove orphan containers in a background thread
+    pub fn start_cleanup_timer(&self, period: Duration) {
+
+        let namespace = self.namespace.to_string();
+        let runner_id = self.runner_id.to_string();
+        let active_scans = self.active_scans.clone();
+
+        thread::spawn(move || {
+            loop {
+                thread::sleep(period);
+
+                if let Err(err) = remove_orphans(&namespace, &runner_id, &active_scans) {
+                    warn!("Could not clean orphan containers ({})", err);
+                }
+            }
+        });
+    }
+
+}
+
+fn remove_orphans(namespace: &str, runner_id: &str, active_scans: &Arc<Mutex<HashSet<String>>>) -> Result<usize, Error> {
+
+    let namespace_arg = format!("--namespace={}", namespace);
+    let output = Command::new("nerdctl")
+        .arg(&namespace_arg)
+        .arg("ps")
+        .arg("--all")
+        .arg("--filter")
+        .arg(format!("label={}={}", RUNNER_LABEL, runner_id))
+        .arg("--format")
+        .arg("{{json .}}")
+        .output()?;
+
+    if !output.status.success() {
+        let stderr_logs = String::from_utf8_lossy(&output.stderr);
+        bail!("Could not list runner containers ({})", stderr_logs.trim());
+    }
+
+    let mut removed_count = 0;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+
+        let listed_container: ListedContainer = match serde_json::from_str(line) {
+            Ok(listed_container) => listed_container,
+            Err(_) => continue
+        };
+
+        let scan_key = find_label(&listed_container.labels, SCAN_LABEL).unwrap_or_default();
+        let is_active = active_scans.lock()
+            .map_err(|_| anyhow!("Active scans lock is poisoned"))?
+            .contains(scan_key);
+        if is_active {
+            continue;
+        }
+
+        info!("Removing orphan container {} (scan {})", listed_container.id, scan_key);
+        let remove_output = Command::new("nerdctl")
+            .arg(&namespace_arg)
+            .arg("rm")
+            .arg("--force")
+            .arg(&listed_container.id)
+            .output()?;
+
+        if remove_output.status.success() {
+            removed_count += 1;
+        }
+        else {
+            let stderr_logs = String::from_utf8_lossy(&remove_output.stderr);
+            warn!("Could not remove orphan container {} ({})", listed_container.id, stderr_logs.trim());
+        }
+    }
+
+    Ok(removed_count)
+}
+
+/// Find a label value in the nerdctl format ("key=value,key=value")
+fn find_label<'a>(labels: &'a str, label_key: &str) -> Option<&'a str> {
+
+    labels.split(',')
+        .filter_map(|label| label.split_once('='))
+        .find(|(key, _)| *key == label_key)
+        .map(|(_, value)| value)
+}
+
+/// Container names only accept alphanumeric characters, dots, dashes and underscores
+fn sanitize_name(raw_name: &str) -> String {
+
+    raw_name.chars()
+        .map(|character| if character.is_ascii_alphanumeric() || character == '.' || character == '_' || character == '-' { character } else { '-' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{find_label, sanitize_name, CancelSignal};
+
+    #[test]
+    fn should_find_label() {
+
+        let labels = "chicon.runner=runner-a,chicon.scan=65f1a2b30001,chicon.stage=1";
+
+        assert_eq!(find_label(labels, "chicon.scan"), Some("65f1a2b30001"));
+        assert_eq!(find_label(labels, "chicon.function"), None);
+    }
+
+    #[test]
+    fn should_sanitize_name() {
+
+        assert_eq!(sanitize_name("runner a/01"), "runner-a-01");
+        assert_eq!(sanitize_name("runner_a.local"), "runner_a.local");
+    }
+
+    #[test]
+    fn should_only_cancel_active_repositories() {
+
+        let cancel_signal = CancelSignal::default();
+        assert!(!cancel_signal.request("repository-a"));
+
+        cancel_signal.begin("repository-a", None);
+        assert!(cancel_signal.request("repository-a"));
+        assert!(cancel_signal.is_requested("repository-a"));
+
+        cancel_signal.end("repository-a");
+        assert!(!cancel_signal.is_requested("repository-a"));
+    }
+
+    #[test]
+    fn should_cancel_scan_requests() {
+
+        let cancel_signal = CancelSignal::default();
+        assert!(!cancel_signal.cancel_request("req-1"));
+
+        cancel_signal.queue("req-1");
+        cancel_signal.queue("req-2");
+        assert!(cancel_signal.cancel_request("req-2"));
+        assert!(cancel_signal.is_cancelled("req-2"));
+
+        cancel_signal.start("req-1");
+        cancel_signal.begin("repository-a", Some("req-1"));
+        assert!(!cancel_signal.is_requested("repository-a"));
+
+        assert!(cancel_signal.cancel_request("req-1"));
+        assert!(cancel_signal.is_requested("repository-a"));
+        cancel_signal.end("repository-a");
+
+        cancel_signal.begin("repository-b", Some("req-1"));
+        assert!(cancel_signal.is_requested("repository-b"));
+
+        cancel_signal.finish("req-1");
+        assert!(!cancel_signal.is_cancelled("req-1"));
+        assert!(!cancel_signal.cancel_request("req-1"));
+    }
+
+}
@@ -0,0 +1,235 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::models::ResourceUsage;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const STATS_PERIOD: Duration = Duration::from_secs(5);
+
+/// Line of `nerdctl stats --format '{{json .}}'`, sizes are human readable
+#[derive(Deserialize)]
+struct ContainerStats {
+
+    #[serde(rename = "MemUsage")]
+    mem_usage: String,  // "12.5MiB / 1GiB"
+
+    #[serde(rename = "BlockIO")]
+    block_io: String    // "1.2MB / 0B"
+
+}
+
+/// Samples the cgroup (v2) statistics of a running container, counters are
+/// cumulative so the last sample gives the stage totals. Without a unified
+/// cgroup hierarchy, memory and I/O are read from nerdctl stats instead
+#[derive(Default)]
+pub struct UsageSampler {
+
+    is_attached: bool,
+
+    cgroup_path: Option<PathBuf>,
+
+    stats_time: Option<Instant>,
+
+    cpu_usec: Option<u64>,
+
+    peak_memory: Option<u64>,
+
+    read_bytes: Option<u64>,
+
+    written_bytes: Option<u64>
+
+}
+
+impl UsageSampler {
+
+    pub fn is_attached(&self) -> bool {
+        self.is_attached
+    }
+
+    /// Attach the sampler to the cgroup of a container process, the container
+    /// is not inspected again even when no cgroup v2 path is found
+    pub fn attach(&mut self, pid: u32) {
+
+        let cgroup_content = fs::read_to_string(format!("/proc/{}/cgroup", pid)).unwrap_or_default();
+
+        self.is_attached = true;
+        self.cgroup_path = parse_cgroup_path(&cgroup_content)
+            .map(|relative_path| PathBuf::from(CGROUP_ROOT).join(relative_path.trim_start_matches('/')));
+    }
+
+    /// Stats are only needed without a cgroup path, and are throttled since each one runs nerdctl
+    pub fn needs_stats(&self) -> bool {
+        self.is_attached && self.cgroup_path.is_none() && self.stats_time.map_or(true, |stats_time| stats_time.elapsed() >= STATS_PERIOD)
+    }
+
+    /// Record a `nerdctl stats` line, CPU time is not available from this source
+    pub fn record_stats(&mut self, stats_content: Option<&str>) {
+
+        self.stats_time = Some(Instant::now());
+
+        let stats = match stats_content.and_then(|content| serde_json::from_str::<ContainerStats>(content.trim()).ok()) {
+            Some(stats) => stats,
+            None => return
+        };
+
+        if let Some((memory, _)) = parse_size_pair(&stats.mem_usage) {
+            self.peak_memory = Some(self.peak_memory.unwrap_or_default().max(memory));
+        }
+        // Counters are cumulative, a stopping container may already report zero
+        if let Some((read_bytes, written_bytes)) = parse_size_pair(&stats.block_io) {
+            self.read_bytes = Some(self.read_bytes.unwrap_or_default().max(read_bytes));
+            self.written_bytes = Some(self.written_bytes.unwrap_or_default().max(written_bytes));
+        }
+    }
+
+    pub fn sample(&mut self) {
+
+        let cgroup_path = match &self.cgroup_path {
+            Some(cgroup_path) => cgroup_path,
+            None => return
+        };
+
+        // The cgroup disappears when the container exits, previous samples are kept
+        if let Ok(cpu_content) = fs::read_to_string(cgroup_path.join("cpu.stat")) {
+            self.cpu_usec = parse_cpu_stat(&cpu_content).or(self.cpu_usec);
+        }
+
+        let memory_content = fs::read_to_string(cgroup_path.join("memory.peak"))
+            .or_else(|_| fs::read_to_string(cgroup_path.join("memory.current")));
+        if let Some(memory) = memory_content.ok().and_then(|content| content.trim().parse::<u64>().ok()) {
+            self.peak_memory = Some(self.peak_memory.unwrap_or_default().max(memory));
+        }
+
+        if let Ok(io_content) = fs::read_to_string(cgroup_path.join("io.stat")) {
+            let (read_bytes, written_bytes) = parse_io_stat(&io_content);
+            self.read_bytes = Some(read_bytes);
+            self.written_bytes = Some(written_bytes);
+        }
+    }
+
+    pub fn finish(self, wall_time_ms: u64) -> ResourceUsage {
+
+        ResourceUsage {
+            wall_time_ms,
+            cpu_time_ms: self.cpu_usec.map(|cpu_usec| cpu_usec / 1000),
+            peak_memory_bytes: self.peak_memory,
+            read_bytes: self.read_bytes,
+            written_bytes: self.written_bytes
+        }
+    }
+
+}
+
+/// Find the unified hierarchy path in a /proc/[pid]/cgroup file
+fn parse_cgroup_path(content: &str) -> Option<String> {
+
+    content.lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| path.trim().to_string())
+}
+
+fn parse_cpu_stat(content: &str) -> Option<u64> {
+
+    content.lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|raw_value| raw_value.trim().parse().ok())
+}
+
+/// Parse a "used / limit" or "read / written" pair of human readable sizes
+fn parse_size_pair(content: &str) -> Option<(u64, u64)> {
+
+    let (first, second) = content.split_once('/')?;
+    Some((parse_size(first)?, parse_size(second)?))
+}
+
+/// Parse a size such as "12.5MiB" or "1.2MB", both binary and decimal units are used by nerdctl
+fn parse_size(content: &str) -> Option<u64> {
+
+    let content = content.trim();
+    let unit_position = content.find(|character: char| character.is_ascii_alphabetic()).unwrap_or(content.len());
+    let (raw_value, unit) = content.split_at(unit_position);
+
+    let multiplier: u64 = match unit {
+        "" | "B" => 1,
+        "kB" | "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None
+    };
+
+    let value: f64 = raw_value.trim().parse().ok()?;
+    Some((value * multiplier as f64) as u64)
+}
+
+/// Sum read and written bytes over all devices of an io.stat file
+fn parse_io_stat(content: &str) -> (u64, u64) {
+
+    let mut read_bytes = 0;
+    let mut written_bytes = 0;
+
+    for field in content.split_whitespace() {
+
+        if let Some(raw_value) = field.strip_prefix("rbytes=") {
+            read_bytes += raw_value.parse::<u64>().unwrap_or_default();
+        }
+        else if let Some(raw_value) = field.strip_prefix("wbytes=") {
+            written_bytes += raw_value.parse::<u64>().unwrap_or_default();
+        }
+    }
+
+    (read_bytes, written_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{parse_cgroup_path, parse_cpu_stat, parse_io_stat, UsageSampler};
+
+    #[test]
+    fn should_parse_cgroup_path() {
+
+        let content = "0::/user.slice/user-1000.slice/nerdctl-8f3c.scope\n";
+
+        assert_eq!(parse_cgroup_path(content), Some("/user.slice/user-1000.slice/nerdctl-8f3c.scope".into()));
+        assert_eq!(parse_cgroup_path("12:memory:/docker/8f3c\n"), None);
+    }
+
+    #[test]
+    fn should_parse_cpu_stat() {
+
+        let content = "usage_usec 1520345\nuser_usec 1200000\nsystem_usec 320345\n";
+
+        assert_eq!(parse_cpu_stat(content), Some(1_520_345));
+    }
+
+    #[test]
+    fn should_parse_io_stat() {
+
+        let content = "8:0 rbytes=1024 wbytes=4096 rios=2 wios=1 dbytes=0 dios=0\n8:16 rbytes=512 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+
+        assert_eq!(parse_io_stat(content), (1536, 4096));
+    }
+
+    #[test]
+    fn should_record_stats() {
+
+        let mut sampler = UsageSampler::default();
+        sampler.record_stats(Some(r#"{"BlockIO":"1.5MB / 4.1kB","CPUPerc":"2.00%","MemUsage":"12MiB / 1GiB","Name":"chicon-a","PIDs":"3"}"#));
+        sampler.record_stats(Some(r#"{"BlockIO":"0B / 0B","CPUPerc":"0.00%","MemUsage":"8MiB / 1GiB","Name":"chicon-a","PIDs":"0"}"#));
+
+        let usage = sampler.finish(1000);
+        assert_eq!(usage.peak_memory_bytes, Some(12 * 1024 * 1024));
+        assert_eq!(usage.read_bytes, Some(1_500_000));
+        assert_eq!(usage.written_bytes, Some(4_100));
+        assert_eq!(usage.cpu_time_ms, None);
+    }
+
+}
@@ -1,16 +1,25 @@
 use std::fs;
-use std::fs::{read_to_string, OpenOptions};
-use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, ErrorKind};
+use std::os::unix::fs::PermissionsExt;
 use std::rc::Rc;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
 
-use anyhow::{Error, bail};
+use anyhow::{anyhow, bail, Error};
 use fs_extra::dir::get_size;
 use log::warn;
+use sha2::{Digest, Sha256};
 
+use crate::utils::open_no_follow;
 use super::config::Config;
 
 const DEFAULT_CACHE: u64 = 100_000_000;
+const CACHES_DIRECTORY: &str = "caches";
+const INDEXES_DIRECTORY: &str = "indexes";    // Issue indexes are small and never pruned
+const RUNNER_ID_FILE: &str = ".runner-id";
+const MAX_REPOSITORY_FILE: u64 = 10_000_000;    // Source files read for fingerprints and suppressions
 
 pub struct Workspace {
 
@@ -20,6 +29,50 @@ pub struct Workspace {
 
 }
 
+/// Writable copy of a repository, the copy is removed once dropped so that
+/// a failed function run does not leave it behind
+pub struct Scratch {
+
+    path: PathBuf,
+
+    is_removed: bool
+
+}
+
+impl Scratch {
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the copy and report failures, dropping the scratch only logs them
+    pub fn remove(mut self) -> Result<(), Error> {
+
+        self.is_removed = true;
+        if self.path.is_dir() {
+            fs::remove_dir_all(&self.path)?;
+        }
+
+        Ok(())
+    }
+
+}
+
+impl Drop for Scratch {
+
+    fn drop(&mut self) {
+
+        if self.is_removed || !self.path.is_dir() {
+            return;
+        }
+
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!("Could not remove scratch directory {} ({})", self.path.display(), err);
+        }
+    }
+
+}
+
 impl Workspace {
 
     pub fn new(config: Rc<Config>) -> Result<Workspace, Error> {
@@ -60,6 +113,7 @@ impl Workspace {
         else {
             fs::remove_dir_all(base_repository.join("bin")).ok();
             fs::remove_dir_all(base_repository.join("result")).ok();
+            fs::remove_dir_all(base_repository.join("scratch")).ok();
         }
     
         if !base_repository.is_dir() {
@@ -82,6 +136,113 @@ impl Workspace {
         Ok(())
     }
 
+    /// Create a disposable writable copy of the repository, reflinks are used
+    /// when the file system supports copy-on-write
+    pub fn create_scratch(&self, repository_id: &str) -> Result<Scratch, Error> {
+
+        let base_repository = &self.base_path.join(repository_id);
+        let scratch_path = base_repository.join("scratch");
+
+        fs::remove_dir_all(&scratch_path).ok();
+
+        let copy_status = Command::new("cp")
+            .arg("--archive")
+            .arg("--reflink=auto")
+            .arg(base_repository.join("repository"))
+            .arg(&scratch_path)
+            .status()?;
+        if !copy_status.success() {
+            fs::remove_dir_all(&scratch_path).ok();
+            bail!("Could not copy repository to scratch directory (status {})", copy_status);
+        }
+
+        Ok(Scratch {
+            path: scratch_path,
+            is_removed: false
+        })
+    }
+
+    /// Find or create a function cache directory, the directory is marked as
+    /// recently used for storage pruning
+    /// Cache directory of a function, shared caches are writable by any container user
+    pub fn get_cache_path(&self, function_id: &str, repository_id: Option<&str>, cache_name: &str, is_shared: bool) -> Result<PathBuf, Error> {
+
+        let is_valid_name = !cache_name.is_empty() && cache_name.chars()
+            .all(|character| character.is_ascii_alphanumeric() || character == '-' || character == '_');
+        if !is_valid_name {
+            bail!("Cache name '{}' should only contain alphanumeric characters, dashes and underscores", cache_name);
+        }
+
+        let cache_key = match repository_id {
+            Some(repository_id) => format!("{}.{}.{}", function_id, repository_id, cache_name),
+            None => format!("{}.{}", function_id, cache_name)
+        };
+        let cache_path = self.base_path.join(CACHES_DIRECTORY).join(cache_key);
+
+        fs::create_dir_all(&cache_path)?;
+        if is_shared {
+            fs::set_permissions(&cache_path, fs::Permissions::from_mode(0o777))?;
+        }
+        File::open(&cache_path)?.set_modified(SystemTime::now())?;
+
+        Ok(cache_path)
+    }
+
+    /// Path of the issue index of a function on a repository, scans of a requested
+    /// reference keep their own index (named after a hash since refs contain slashes)
+    fn get_index_path(&self, function_id: &str, repository_id: &str, git_ref: Option<&str>) -> Result<PathBuf, Error> {
+
+        let index_key = match git_ref {
+            Some(git_ref) => {
+                let ref_hash = format!("{:x}", Sha256::digest(git_ref.as_bytes()));
+                format!("{}.{}.{}.json", function_id, repository_id, &ref_hash[..16])
+            },
+            None => format!("{}.{}.json", function_id, repository_id)
+        };
+        if Path::new(&index_key).components().count() != 1 {
+            bail!("Invalid issue index key '{}'", index_key);
+        }
+
+        Ok(self.base_path.join(INDEXES_DIRECTORY).join(index_key))
+    }
+
+    /// Identifier generated on the first run and kept with the workspace, so that
+    /// runners sharing a host and namespace never remove each other's containers
+    pub fn get_runner_id(&self) -> Result<String, Error> {
+
+        let runner_id_path = self.base_path.join(RUNNER_ID_FILE);
+        match fs::read_to_string(&runner_id_path) {
+            Ok(runner_id) if !runner_id.trim().is_empty() => return Ok(runner_id.trim().to_string()),
+            Ok(_) => {},
+            Err(err) if err.kind() == ErrorKind::NotFound => {},
+            Err(err) => return Err(err.into())
+        }
+
+        let mut random_bytes = [0u8; 8];
+        File::open("/dev/urandom")?.read_exact(&mut random_bytes)?;
+        let runner_id = format!("runner-{}", random_bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>());
+
+        fs::write(&runner_id_path, &runner_id)?;
+        Ok(runner_id)
+    }
+
+    pub fn read_index(&self, function_id: &str, repository_id: &str, git_ref: Option<&str>) -> Result<Option<String>, Error> {
+
+        match fs::read_to_string(self.get_index_path(function_id, repository_id, git_ref)?) {
+            Ok(index_content) => Ok(Some(index_content)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into())
+        }
+    }
+
+    pub fn write_index(&self, function_id: &str, repository_id: &str, git_ref: Option<&str>, content: &str) -> Result<(), Error> {
+
+        fs::create_dir_all(self.base_path.join(INDEXES_DIRECTORY))?;
+        fs::write(self.get_index_path(function_id, repository_id, git_ref)?, content)?;
+
+        Ok(())
+    }
+
     pub fn write_string(&self, repository_id: &str, relative_path: &str, content: &str) -> Result<(), Error> {
 
         let absolute_path = &self.base_path.join(repository_id).join(relative_path);
@@ -94,13 +255,83 @@ impl Workspace {
         Ok(())
     }
 
-    pub fn read_string(&self, repository_id: &str, relative_path: &str) -> Result<String, Error> {
+    /// Write a file in the bin directory, nested directories are created
+    pub fn write_bin_file(&self, repository_id: &str, relative_path: &str, content: &str, mode: u32) -> Result<(), Error> {
 
-        let absolute_path = &self.base_path.join(repository_id).join(relative_path);
+        let file_path = Path::new(relative_path);
+        let is_contained = file_path.components().all(|component| matches!(component, Component::Normal(_)));
+        if !is_contained || relative_path.is_empty() {
+            bail!("Stage file path '{}' should be relative to the bin directory", relative_path);
+        }
+
+        let absolute_path = self.base_path.join(repository_id).join("bin").join(file_path);
+        if let Some(parent_path) = absolute_path.parent() {
+            fs::create_dir_all(parent_path)?;
+        }
+
+        fs::write(&absolute_path, content)?;
+        fs::set_permissions(&absolute_path, fs::Permissions::from_mode(mode))?;
+
+        Ok(())
+    }
+
+    /// Read a line range of a file in the repository checkout, lines are 1-based and inclusive
+    pub fn read_source_lines(&self, repository_id: &str, relative_path: &str, start_line: u32, end_line: u32) -> Result<String, Error> {
+
+        if start_line == 0 || end_line < start_line {
+            bail!("Invalid source location {}:{}-{}", relative_path, start_line, end_line);
+        }
+
+        let file_content = self.read_repository_file(repository_id, relative_path)?
+            .ok_or_else(|| anyhow!("Source file {} not found", relative_path))?;
 
-        let file_content = read_to_string(absolute_path)?;
+        let source_lines: Vec<&str> = file_content.lines()
+            .skip(start_line as usize - 1)
+            .take((end_line - start_line + 1) as usize)
+            .collect();
+        Ok(source_lines.join("\n"))
+    }
+
+    /// Read a file of the repository checkout, the repository is not trusted: links
+    /// are not followed (including parent directories) and large files are refused
+    pub fn read_repository_file(&self, repository_id: &str, relative_path: &str) -> Result<Option<String>, Error> {
+
+        let file_path = Path::new(relative_path);
+        let is_contained = file_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !is_contained || relative_path.is_empty() {
+            bail!("Path '{}' should be relative to the repository", relative_path);
+        }
+
+        // Git materializes symbolic links, parent directories are checked before opening the last component
+        let repository_path = self.base_path.join(repository_id).join("repository");
+        for parent_path in file_path.ancestors().skip(1).filter(|parent_path| !parent_path.as_os_str().is_empty()) {
+            match fs::symlink_metadata(repository_path.join(parent_path)) {
+                Ok(metadata) if metadata.is_dir() => {},
+                Ok(_) => bail!("Repository path '{}' is not a directory", parent_path.display()),
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into())
+            }
+        }
 
-        Ok(file_content)
+        let absolute_path = repository_path.join(file_path);
+        let repository_file = match open_no_follow(&absolute_path) {
+            Ok(repository_file) => repository_file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into())
+        };
+
+        let metadata = repository_file.metadata()?;
+        if !metadata.is_file() || metadata.len() > MAX_REPOSITORY_FILE {
+            bail!("Repository file '{}' is not a regular file or is too large", relative_path);
+        }
+
+        let mut file_content = String::new();
+        repository_file.take(MAX_REPOSITORY_FILE).read_to_string(&mut file_content)?;
+        Ok(Some(file_content))
+    }
+
+    pub fn get_result_path(&self, repository_id: &str) -> PathBuf {
+        self.base_path.join(repository_id).join("result")
     }
 
     pub fn get_total_usage(&self) -> Result<u64, Error> {
@@ -110,6 +341,13 @@ impl Workspace {
         Ok(workspace_size)
     }
 
+    pub fn is_full(&self) -> Result<bool, Error> {
+
+        let current_usage = self.get_total_usage()?;
+
+        Ok(current_usage >= self.cache_size)
+    }
+
     pub fn prune_storage(&self) -> Result<(), Error> {
 
         for _ in 0..10 {
@@ -122,13 +360,13 @@ impl Workspace {
             let storage_mb = current_usage / 1_000_000;
             warn!("Storage is over cache limit ({}Mb), selecting a path to delete", storage_mb);
     
-            let paths = fs::read_dir(&self.base_path)?;
-            let potential_dir = paths.into_iter()
-                .find(|path| path.as_ref().unwrap().path().is_dir());
+            // Repositories and function caches are evicted, least recently used first
+            let potential_dir = self.list_prunable_dirs()?.into_iter()
+                .min_by_key(|(_, modified)| *modified);
 
             match potential_dir {
-                Some(trashed_dir) => {
-                    fs::remove_dir_all(trashed_dir.unwrap().path())?;
+                Some((trashed_dir, _)) => {
+                    fs::remove_dir_all(trashed_dir)?;
                 },
                 None => {
                     warn!("Could not find a directory to delete in the workspace");
@@ -139,4 +377,153 @@ impl Workspace {
         Ok(())
     }
 
+    fn list_prunable_dirs(&self) -> Result<Vec<(PathBuf, SystemTime)>, Error> {
+
+        let caches_path = self.base_path.join(CACHES_DIRECTORY);
+        let mut candidate_paths: Vec<PathBuf> = vec![];
+
+        for entry in fs::read_dir(&self.base_path)? {
+
+            let entry_path = entry?.path();
+            if entry_path.is_dir() && entry_path != caches_path && !entry_path.ends_with(INDEXES_DIRECTORY) {
+                candidate_paths.push(entry_path);
+            }
+        }
+
+        if caches_path.is_dir() {
+            for entry in fs::read_dir(&caches_path)? {
+
+                let entry_path = entry?.path();
+                if entry_path.is_dir() {
+                    candidate_paths.push(entry_path);
+                }
+            }
+        }
+
+        let candidates = candidate_paths.into_iter()
+            .map(|candidate_path| {
+                let modified = fs::metadata(&candidate_path)
+                    .and_then(|metadata| metadata.modified())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                (candidate_path, modified)
+            })
+            .collect();
+        Ok(candidates)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+    use std::rc::Rc;
+
+    use anyhow::Error;
+    use crate::components::config::Config;
+    use crate::test_utils::TempDir;
+    use super::Workspace;
+
+    #[test]
+    fn should_not_follow_repository_links() -> Result<(), Error> {
+
+        let workspace_dir = TempDir::new("workspace")?;
+        let repository_path = workspace_dir.path().join("repo").join("repository");
+        fs::create_dir_all(repository_path.join("src"))?;
+        fs::write(repository_path.join("src/main.py"), "print(1)")?;
+        fs::write(workspace_dir.path().join("secret"), "host file")?;
+        symlink(workspace_dir.path(), repository_path.join("escape"))?;
+        symlink(workspace_dir.path().join("secret"), repository_path.join("secret"))?;
+
+        let mut config = Config::default();
+        config.set_workspace_path(workspace_dir.path().to_str().unwrap_or_default());
+        let workspace = Workspace::new(Rc::new(config))?;
+
+        assert_eq!(workspace.read_repository_file("repo", "src/main.py")?.as_deref(), Some("print(1)"));
+        assert_eq!(workspace.read_repository_file("repo", "src/missing.py")?, None);
+        assert!(workspace.read_repository_file("repo", "escape/secret").is_err());
+        assert!(workspace.read_repository_file("repo", "secret").is_err());
+        assert!(workspace.read_repository_file("repo", "../secret").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_remove_dropped_scratch() -> Result<(), Error> {
+
+        let workspace_dir = TempDir::new("workspace")?;
+        let repository_path = workspace_dir.path().join("repo").join("repository");
+        fs::create_dir_all(&repository_path)?;
+        fs::write(repository_path.join("main.py"), "print(1)")?;
+
+        let mut config = Config::default();
+        config.set_workspace_path(workspace_dir.path().to_str().unwrap_or_default());
+        let workspace = Workspace::new(Rc::new(config))?;
+
+        let scratch = workspace.create_scratch("repo")?;
+        let scratch_path = scratch.path().to_path_buf();
+        assert!(scratch_path.join("main.py").is_file());
+
+        drop(scratch);
+        assert!(!scratch_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_keep_index_per_reference() -> Result<(), Error> {
+
+        let workspace_dir = TempDir::new("workspace")?;
+
+        let mut config = Config::default();
+        config.set_workspace_path(workspace_dir.path().to_str().unwrap_or_default());
+        let workspace = Workspace::new(Rc::new(config))?;
+
+        workspace.write_index("function", "repo", None, "default")?;
+        workspace.write_index("function", "repo", Some("release/1.2"), "release")?;
+
+        assert_eq!(workspace.read_index("function", "repo", None)?.as_deref(), Some("default"));
+        assert_eq!(workspace.read_index("function", "repo", Some("release/1.2"))?.as_deref(), Some("release"));
+        assert_eq!(workspace.read_index("function", "repo", Some("main"))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_persist_runner_id() -> Result<(), Error> {
+
+        let workspace_dir = TempDir::new("workspace")?;
+
+        let mut config = Config::default();
+        config.set_workspace_path(workspace_dir.path().to_str().unwrap_or_default());
+        let workspace = Workspace::new(Rc::new(config))?;
+
+        let runner_id = workspace.get_runner_id()?;
+        assert!(runner_id.starts_with("runner-"));
+        assert_eq!(workspace.get_runner_id()?, runner_id);
+
+        let other_dir = TempDir::new("workspace")?;
+        let mut other_config = Config::default();
+        other_config.set_workspace_path(other_dir.path().to_str().unwrap_or_default());
+        assert_ne!(Workspace::new(Rc::new(other_config))?.get_runner_id()?, runner_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_share_cache_with_any_user() -> Result<(), Error> {
+
+        let workspace_dir = TempDir::new("workspace")?;
+
+        let mut config = Config::default();
+        config.set_workspace_path(workspace_dir.path().to_str().unwrap_or_default());
+        let workspace = Workspace::new(Rc::new(config))?;
+
+        let shared_path = workspace.get_cache_path("function", Some("repo"), "shared", true)?;
+        assert_eq!(fs::metadata(shared_path)?.permissions().mode() & 0o777, 0o777);
+
+        Ok(())
+    }
+
 }
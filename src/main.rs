@@ -3,6 +3,10 @@ mod models;
 mod utils;
 mod commands;
 mod components;
+mod parsers;
+
+#[cfg(test)]
+mod test_utils;
 
 use std::process;
 
@@ -1,31 +1,126 @@
+use std::collections::BTreeMap;
 use std::path::Path;
 use std::rc::Rc;
 
 use anyhow::{bail, Error};
 use git2::{RemoteCallbacks, Cred};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use crate::components::config::Config;
+use crate::components::config::{Config, PullPolicy};
+use crate::parsers::{ReportFormat, sbom::SbomFormat};
 
-#[derive(Deserialize, Serialize)]
+/// Metric written by a function, integers are tried before floats
+#[derive(Deserialize, Serialize, Clone, PartialEq, Debug)]
 #[serde(untagged)]
 pub enum MetricValue {
 
     Number(i64),
 
+    Float(f64),
+
     Text(String),
 
-    Boolean(bool)
+    Boolean(bool),
+
+    List(Vec<MetricValue>),
+
+    Table(BTreeMap<String, MetricValue>)
+
+}
+
+impl MetricValue {
+
+    pub fn type_name(&self) -> &'static str {
+
+        match self {
+            MetricValue::Number(_) => "number",
+            MetricValue::Float(_) => "float",
+            MetricValue::Text(_) => "text",
+            MetricValue::Boolean(_) => "boolean",
+            MetricValue::List(_) => "list",
+            MetricValue::Table(_) => "table"
+        }
+    }
 
 }
 
-#[derive(Deserialize, Serialize)]
+/// Expected type of a function output
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+
+    Number,
+
+    Float,
+
+    Text,
+
+    Boolean,
+
+    List,
+
+    Table
+
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum DiffStatus {
+
+    New,
+
+    Unchanged,
+
+    Resolved
+
+}
+
+#[derive(Deserialize, Serialize, Default)]
 pub struct CodeIssue {
 
     pub name: String,
 
     pub severity: Option<String>,
 
+    pub path: Option<String>,   // Relative to the repository root
+
+    #[serde(rename = "startLine", alias = "start_line")]
+    pub start_line: Option<u32>,
+
+    #[serde(rename = "endLine", alias = "end_line")]
+    pub end_line: Option<u32>,
+
+    #[serde(rename = "startColumn", alias = "start_column")]
+    pub start_column: Option<u32>,
+
+    #[serde(rename = "endColumn", alias = "end_column")]
+    pub end_column: Option<u32>,
+
+    #[serde(rename = "ruleId", alias = "rule_id")]
+    pub rule_id: Option<String>,
+
+    pub message: Option<String>,
+
+    #[serde(default)]
+    pub references: Vec<String>,    // CWE and CVE identifiers
+
+    pub remediation: Option<String>,
+
+    #[serde(skip_serializing)]
+    pub snippet: Option<String>,    // Only used for fingerprints, may contain secrets
+
+    pub fingerprint: Option<String>,
+
+    #[serde(rename = "isSuppressed", skip_deserializing)]
+    pub is_suppressed: bool,    // Set by the runner from the repository suppressions
+
+    #[serde(rename = "suppressionJustification", skip_deserializing)]
+    pub suppression_justification: Option<String>,
+
+    #[serde(rename = "diffStatus", skip_deserializing)]
+    pub diff_status: Option<DiffStatus>,    // Compared to the last successful scan of the function
+
     #[serde(rename = "repositoryId")]
     pub repository_id: Option<String>,  // Fields are marked as optional since they are being used by TOML
                                         // note that this may cause security issues (TODO)
@@ -38,6 +133,27 @@ pub struct CodeIssue {
 
 }
 
+impl CodeIssue {
+
+    /// Compute a stable fingerprint from the rule, the path and the snippet,
+    /// whitespaces are normalized so that indentation changes keep the fingerprint
+    pub fn compute_fingerprint(&self, snippet: Option<&str>) -> String {
+
+        let rule = self.rule_id.as_deref().unwrap_or(&self.name);
+        let path = self.path.as_deref().unwrap_or_default();
+        let normalized_snippet = snippet.unwrap_or_default()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}\n{}\n{}", rule, path, normalized_snippet));
+
+        format!("{:x}", hasher.finalize())
+    }
+
+}
+
 #[derive(Serialize)]
 pub struct MassIssues {
 
@@ -45,6 +161,35 @@ pub struct MassIssues {
 
 }
 
+/// Component listed in a software bill of materials
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct SbomComponent {
+
+    pub name: String,
+
+    pub version: Option<String>,
+
+    pub purl: Option<String>,
+
+    #[serde(rename = "type")]
+    pub component_type: Option<String>,
+
+    pub licenses: Vec<String>   // SPDX identifiers or expressions when available
+
+}
+
+/// Normalized components of an SBOM written by a function
+#[derive(Serialize)]
+pub struct ScanComponents {
+
+    pub file: String,
+
+    pub format: SbomFormat,
+
+    pub components: Vec<SbomComponent>
+
+}
+
 
 #[derive(Deserialize)]
 pub struct GenericModel {
@@ -54,6 +199,28 @@ pub struct GenericModel {
 
 }
 
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum FailureReason {
+
+    CloneError,
+
+    ImagePullError,
+
+    Timeout,
+
+    OomKilled,
+
+    NonZeroExit,
+
+    ResultValidationError,
+
+    RunnerInternalError,
+
+    QualityGateFailed
+
+}
+
 #[derive(Serialize)]
 pub struct Scan {
 
@@ -63,17 +230,145 @@ pub struct Scan {
     #[serde(rename = "repositoryId")]
     pub repository_id: String,
 
-    pub commit: GitCommit,
+    pub commit: Option<GitCommit>,    // Missing when the scan failed before the clone
 
     #[serde(rename = "hasFailed")]
     pub has_failed: bool,
 
+    #[serde(rename = "failureReason")]
+    pub failure_reason: Option<FailureReason>,
+
+    #[serde(rename = "errorMessage")]
+    pub error_message: Option<String>,
+
+    #[serde(rename = "isCancelled")]
+    pub is_cancelled: bool,
+
+    #[serde(rename = "isTruncated")]
+    pub is_truncated: bool,     // Issues or metrics above the runner limits were dropped
+
     pub logs: String,
 
     #[serde(rename = "timingMs")]
     pub timing_ms: usize,
 
-    pub results: Vec<ScanMetadata>
+    pub results: Vec<ScanMetadata>,
+
+    pub stages: Vec<ScanStage>,
+
+    #[serde(rename = "gateViolations")]
+    pub gate_violations: Vec<GateViolation>
+
+}
+
+/// Resources consumed by a stage container (missing values could not be measured)
+#[derive(Serialize, Clone, Default)]
+pub struct ResourceUsage {
+
+    #[serde(rename = "wallTimeMs")]
+    pub wall_time_ms: u64,
+
+    #[serde(rename = "cpuTimeMs")]
+    pub cpu_time_ms: Option<u64>,
+
+    #[serde(rename = "peakMemoryBytes")]
+    pub peak_memory_bytes: Option<u64>,
+
+    #[serde(rename = "readBytes")]
+    pub read_bytes: Option<u64>,
+
+    #[serde(rename = "writtenBytes")]
+    pub written_bytes: Option<u64>
+
+}
+
+impl ResourceUsage {
+
+    /// Accumulate another usage, the peak memory is kept as a maximum
+    pub fn add(&mut self, other: &ResourceUsage) {
+
+        self.wall_time_ms += other.wall_time_ms;
+        self.cpu_time_ms = sum_optional(self.cpu_time_ms, other.cpu_time_ms);
+        self.peak_memory_bytes = self.peak_memory_bytes.max(other.peak_memory_bytes);
+        self.read_bytes = sum_optional(self.read_bytes, other.read_bytes);
+        self.written_bytes = sum_optional(self.written_bytes, other.written_bytes);
+    }
+
+}
+
+fn sum_optional(first: Option<u64>, second: Option<u64>) -> Option<u64> {
+
+    match (first, second) {
+        (None, None) => None,
+        _ => Some(first.unwrap_or_default() + second.unwrap_or_default())
+    }
+}
+
+/// Quality gate evaluated once the function has run, either on the issues of a
+/// severity (at most "maximum" issues) or on a numeric metric (between bounds)
+#[derive(Deserialize, Clone, Default, Debug)]
+pub struct QualityGate {
+
+    pub severity: Option<String>,
+
+    pub metric: Option<String>,
+
+    pub minimum: Option<f64>,
+
+    pub maximum: Option<f64>,
+
+    pub function: Option<String>    // Function ID or name, only used by repository gates
+
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub struct GateViolation {
+
+    pub gate: String,
+
+    pub actual: Option<f64>,    // Missing when the metric was not found
+
+    pub threshold: f64,
+
+    pub message: String
+
+}
+
+#[derive(Serialize)]
+pub struct ScanStage {
+
+    pub index: usize,
+
+    pub environment: String,
+
+    #[serde(rename = "exitCode")]
+    pub exit_code: Option<i32>,
+
+    pub usage: ResourceUsage
+
+}
+
+impl Scan {
+
+    /// Build a failed scan for errors preventing the function execution
+    pub fn failed(function_id: &str, repository_id: &str, commit: Option<GitCommit>, failure_reason: FailureReason, error_message: String) -> Scan {
+
+        Scan {
+            function_id: function_id.to_string(),
+            repository_id: repository_id.to_string(),
+            commit,
+            has_failed: true,
+            failure_reason: Some(failure_reason),
+            error_message: Some(error_message),
+            is_cancelled: false,
+            is_truncated: false,
+            logs: "".to_string(),
+            timing_ms: 0,
+            results: vec![],
+            stages: vec![],
+            gate_violations: vec![]
+        }
+    }
 
 }
 
@@ -84,7 +379,9 @@ pub struct ScanMetadata {
 
     pub description: String,
 
-    pub value: MetricValue
+    pub value: MetricValue,
+
+    pub unit: Option<String>
 
 }
 
@@ -110,7 +407,25 @@ pub struct FunctionCapabilities {
 
     pub network: bool,
 
-    pub filesystem: bool
+    pub filesystem: bool,
+
+    #[serde(default)]
+    pub egress: Vec<String>,    // Allowed hosts or CIDRs, enforced by the egress proxy
+
+    #[serde(rename = "writableWorkspace", default)]
+    pub writable_workspace: bool    // Disposable writable copy of the repository
+
+}
+
+/// Handling of invalid function results (parse errors, undeclared or missing outputs)
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultValidation {
+
+    Fail,
+
+    #[default]
+    Warn
 
 }
 
@@ -124,9 +439,64 @@ pub struct CodeFunction {
 
     pub capabilities: FunctionCapabilities,
 
+    #[serde(rename = "pullPolicy", default)]
+    pub pull_policy: Option<PullPolicy>,   // Overrides the runner pull policy
+
+    #[serde(rename = "stageTimeout", default)]
+    pub stage_timeout: Option<u64>,     // Seconds, overrides the runner stage timeout
+
     pub outputs: Vec<FunctionOutput>,
 
-    pub stages: Vec<FunctionStage>
+    #[serde(rename = "resultValidation", default)]
+    pub result_validation: ResultValidation,
+
+    pub stages: Vec<FunctionStage>,
+
+    #[serde(default)]
+    pub caches: Vec<FunctionCache>,
+
+    #[serde(default)]
+    pub reports: Vec<FunctionReport>,
+
+    #[serde(default)]
+    pub artifacts: Vec<String>, // Glob patterns relative to the result directory
+
+    #[serde(default)]
+    pub gates: Vec<QualityGate>
+
+}
+
+/// Scanner report written in /result and parsed by the runner
+#[derive(Deserialize)]
+pub struct FunctionReport {
+
+    pub file: String,   // Path relative to the result directory
+
+    pub format: Option<ReportFormat>    // Detected from the content when missing
+
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheScope {
+
+    #[default]
+    Function,
+
+    Repository
+
+}
+
+/// Named cache volume kept across scans (vulnerability databases, dependencies, ...)
+#[derive(Deserialize)]
+pub struct FunctionCache {
+
+    pub name: String,
+
+    pub path: String,   // Mount path in the container
+
+    #[serde(default)]
+    pub scope: CacheScope
 
 }
 
@@ -135,17 +505,50 @@ pub struct FunctionStage {
 
     pub environment: FunctionEnv,
 
+    #[serde(default)]
     pub content: String,
 
+    #[serde(default)]
+    pub arguments: Vec<String>,
+
+    pub entrypoint: Option<Vec<String>>,    // Replaces the executor and stage script
+
+    #[serde(default)]
+    pub files: Vec<StageFile>
+
 }
 
+/// Additional file materialized in the bin directory (/tmp-bin)
 #[derive(Deserialize)]
+pub struct StageFile {
+
+    pub path: String,
+
+    pub content: String,
+
+    pub mode: Option<String>    // Octal file mode such as "0755"
+
+}
+
+#[derive(Deserialize, Default)]
 pub struct FunctionOutput {
 
     pub key: String,
 
     pub description: String,
 
+    #[serde(rename = "type", default)]
+    pub metric_type: Option<MetricType>,   // Values are coerced to this type when possible
+
+    #[serde(default)]
+    pub unit: Option<String>,
+
+    #[serde(default)]
+    pub minimum: Option<f64>,
+
+    #[serde(default)]
+    pub maximum: Option<f64>
+
 }
 
 #[derive(Deserialize)]
@@ -190,7 +593,9 @@ impl<'repo> From<git2::Commit<'repo>> for GitCommit {
 
 impl Repository {
 
-    pub fn pull_or_clone(&self, config: Rc<Config>) -> Result<GitCommit, Error> {
+    /// Update the local copy of the default branch, or check out a detached
+    /// reference when the scan request asks for one
+    pub fn pull_or_clone(&self, config: Rc<Config>, git_ref: Option<&str>) -> Result<GitCommit, Error> {
 
         let default_branch = "master";  // TODO
 
@@ -200,7 +605,16 @@ impl Repository {
         if git_path.is_dir() {
 
             let existing = git2::Repository::open(repository_path)?;
-            existing.find_remote("origin")?.fetch(&[default_branch], None, None)?;
+            if let Some(git_ref) = git_ref {
+                return checkout_ref(&config, &existing, git_ref);
+            }
+
+            // A previous scan may have left a detached reference checked out
+            if existing.head_detached()? {
+                existing.set_head(&format!("refs/heads/{}", default_branch))?;
+                existing.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+            }
+            existing.find_remote("origin")?.fetch(&[default_branch], Some(&mut fetch_options(&config)), None)?;
 
             let fetch_head = existing.find_reference("FETCH_HEAD")?;
             let fetch_commit = existing.reference_to_annotated_commit(&fetch_head)?;
@@ -228,26 +642,12 @@ impl Repository {
 
         // Prepare builder.
         let mut builder = git2::build::RepoBuilder::new();
-
-        if let Some(ssh_clone_key) = &config.workspace.ssh_clone_key {
-        
-            // Prepare callbacks.
-            let mut callbacks = RemoteCallbacks::new();
-            callbacks.credentials(|_url, username_from_url, _allowed_types| {
-                Cred::ssh_key(
-                    username_from_url.unwrap_or("git"), None,
-                    Path::new(ssh_clone_key), None
-                )
-            });
-
-            // Prepare fetch options.
-            let mut fo = git2::FetchOptions::new();
-            fo.remote_callbacks(callbacks);
-            builder.fetch_options(fo);
-
-        }
+        builder.fetch_options(fetch_options(&config));
 
         let cloned = builder.clone(&self.url, &repository_path)?;
+        if let Some(git_ref) = git_ref {
+            return checkout_ref(&config, &cloned, git_ref);
+        }
 
         let fetch_head = cloned.find_reference("HEAD")?;
         let fetch_commit = cloned.reference_to_annotated_commit(&fetch_head)?;
@@ -257,3 +657,88 @@ impl Repository {
     }
 
 }
+
+/// Fetch options authenticated with the runner SSH key when configured
+fn fetch_options(config: &Config) -> git2::FetchOptions<'_> {
+
+    let mut fo = git2::FetchOptions::new();
+
+    if let Some(ssh_clone_key) = &config.workspace.ssh_clone_key {
+
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(|_url, username_from_url, _allowed_types| {
+            Cred::ssh_key(
+                username_from_url.unwrap_or("git"), None,
+                Path::new(ssh_clone_key), None
+            )
+        });
+        fo.remote_callbacks(callbacks);
+    }
+
+    fo
+}
+
+/// Fetch a branch, tag or commit from the origin and check it out as a detached head,
+/// the requested reference is submitted as the scan branch
+fn checkout_ref(config: &Config, repository: &git2::Repository, git_ref: &str) -> Result<GitCommit, Error> {
+
+    repository.find_remote("origin")?.fetch(&[git_ref], Some(&mut fetch_options(config)), None)?;
+
+    let fetch_head = repository.find_reference("FETCH_HEAD")?;
+    let fetch_commit = repository.reference_to_annotated_commit(&fetch_head)?;
+    repository.set_head_detached(fetch_commit.id())?;
+    repository.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+
+    let mut commit: GitCommit = repository.find_commit(fetch_commit.id())?.into();
+    commit.branch = git_ref.to_string();
+
+    Ok(commit)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use crate::components::config::Config;
+    use crate::test_utils::TempDir;
+    use super::{checkout_ref, CodeIssue};
+
+    #[test]
+    fn should_compute_stable_fingerprint() {
+
+        let issue = CodeIssue {
+            name: "Hardcoded password".into(),
+            path: Some("src/config.py".into()),
+            rule_id: Some("hardcoded-password".into()),
+            ..Default::default()
+        };
+
+        let fingerprint = issue.compute_fingerprint(Some("password =  \"changeme\"\n"));
+
+        assert_eq!(fingerprint, issue.compute_fingerprint(Some("  password = \"changeme\"")));
+        assert_ne!(fingerprint, issue.compute_fingerprint(Some("password = \"other\"")));
+    }
+
+    #[test]
+    fn should_submit_requested_ref_as_branch() -> Result<(), Error> {
+
+        let origin_dir = TempDir::new("origin")?;
+        let origin = git2::Repository::init(origin_dir.path())?;
+        let signature = git2::Signature::now("Runner", "runner@localhost")?;
+        let tree_id = origin.index()?.write_tree()?;
+        let tree = origin.find_tree(tree_id)?;
+        let commit_id = origin.commit(Some("refs/heads/release/1.2"), &signature, &signature, "Release", &tree, &[])?;
+
+        let clone_dir = TempDir::new("clone")?;
+        let cloned = git2::Repository::init(clone_dir.path())?;
+        cloned.remote("origin", origin_dir.path().to_str().unwrap_or_default())?;
+
+        let commit = checkout_ref(&Config::default(), &cloned, "release/1.2")?;
+
+        assert_eq!(commit.commit_id, commit_id.to_string());
+        assert_eq!(commit.branch, "release/1.2");
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,93 @@
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+use crate::models::CodeIssue;
+use super::normalize_path;
+
+// Leaked secrets are always reported with a high severity
+const SECRET_SEVERITY: &str = "high";
+
+#[derive(Deserialize)]
+struct GitleaksFinding {
+
+    #[serde(rename = "RuleID")]
+    rule_id: String,
+
+    #[serde(rename = "Description")]
+    description: Option<String>,
+
+    #[serde(rename = "File")]
+    file: Option<String>,
+
+    #[serde(rename = "StartLine")]
+    start_line: Option<u32>,
+
+    #[serde(rename = "EndLine")]
+    end_line: Option<u32>,
+
+    #[serde(rename = "StartColumn")]
+    start_column: Option<u32>,
+
+    #[serde(rename = "EndColumn")]
+    end_column: Option<u32>,
+
+    #[serde(rename = "Fingerprint")]
+    fingerprint: Option<String>
+
+}
+
+/// Parse a Gitleaks JSON report (gitleaks detect --report-format json)
+pub fn parse(content: &str) -> Result<Vec<CodeIssue>, Error> {
+
+    let findings: Vec<GitleaksFinding> = serde_json::from_str(content)?;
+
+    // The secret and its match are never kept, only the location is reported
+    let issues = findings.into_iter()
+        .map(|finding| CodeIssue {
+            name: finding.rule_id.to_string(),
+            severity: Some(SECRET_SEVERITY.to_string()),
+            path: finding.file.map(|file| normalize_path(&file)),
+            start_line: finding.start_line,
+            end_line: finding.end_line,
+            start_column: finding.start_column,
+            end_column: finding.end_column,
+            rule_id: Some(finding.rule_id),
+            message: finding.description,
+            remediation: Some("Revoke the secret and remove it from the repository history".to_string()),
+            fingerprint: finding.fingerprint,
+            ..Default::default()
+        })
+        .collect();
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use super::parse;
+
+    #[test]
+    fn should_parse_gitleaks_findings() -> Result<(), Error> {
+
+        let content = r#"[
+            {
+                "Description": "AWS Access Token", "StartLine": 3, "EndLine": 3, "StartColumn": 14, "EndColumn": 33,
+                "File": "config.js", "RuleID": "aws-access-token", "Secret": "REDACTED",
+                "Fingerprint": "config.js:aws-access-token:3"
+            }
+        ]"#;
+
+        let issues = parse(content)?;
+
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].name, "aws-access-token");
+        assert_eq!(issues[0].severity.as_deref(), Some("high"));
+        assert_eq!(issues[0].path.as_deref(), Some("config.js"));
+        assert_eq!(issues[0].start_column, Some(14));
+        assert_eq!(issues[0].fingerprint.as_deref(), Some("config.js:aws-access-token:3"));
+
+        Ok(())
+    }
+
+}
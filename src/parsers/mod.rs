@@ -0,0 +1,104 @@
+pub mod sarif;
+pub mod semgrep;
+pub mod trivy;
+pub mod gitleaks;
+pub mod sbom;
+
+use anyhow::{Error, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::models::CodeIssue;
+
+/// Standard scanner output formats understood by the runner
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+
+    Sarif,
+
+    Semgrep,
+
+    Trivy,
+
+    Gitleaks
+
+}
+
+/// Detect a report format based on the JSON document structure
+pub fn detect_format(content: &str) -> Option<ReportFormat> {
+
+    let document: Value = serde_json::from_str(content).ok()?;
+
+    if document.get("runs").is_some_and(Value::is_array) && document.get("version").is_some() {
+        return Some(ReportFormat::Sarif);
+    }
+    if document.get("SchemaVersion").is_some() && (document.get("Results").is_some() || document.get("ArtifactName").is_some()) {
+        return Some(ReportFormat::Trivy);
+    }
+
+    let has_semgrep_results = document.get("results")
+        .and_then(Value::as_array)
+        .is_some_and(|results| results.iter().all(|result| result.get("check_id").is_some()));
+    if has_semgrep_results && document.get("errors").is_some() {
+        return Some(ReportFormat::Semgrep);
+    }
+
+    // An empty array carries no Gitleaks specific key and is left undetected
+    let has_gitleaks_findings = document.as_array()
+        .and_then(|findings| findings.first())
+        .is_some_and(|finding| finding.get("RuleID").is_some() && finding.get("Match").is_some());
+    if has_gitleaks_findings {
+        return Some(ReportFormat::Gitleaks);
+    }
+
+    None
+}
+
+pub fn parse_report(format: ReportFormat, content: &str) -> Result<Vec<CodeIssue>, Error> {
+
+    match format {
+        ReportFormat::Sarif => sarif::parse(content),
+        ReportFormat::Semgrep => semgrep::parse(content),
+        ReportFormat::Trivy => trivy::parse(content),
+        ReportFormat::Gitleaks => gitleaks::parse(content)
+    }
+}
+
+/// Convert a scanner path or URI into a path relative to the repository root
+/// (the repository is mounted in /workspace)
+pub fn normalize_path(raw_path: &str) -> String {
+
+    let without_scheme = raw_path.strip_prefix("file://").unwrap_or(raw_path);
+    let relative_path = without_scheme.strip_prefix("/workspace/").unwrap_or(without_scheme);
+
+    relative_path.trim_start_matches("./").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{detect_format, normalize_path, ReportFormat};
+
+    #[test]
+    fn should_detect_formats() {
+
+        assert_eq!(detect_format(r#"{"version": "2.1.0", "runs": []}"#), Some(ReportFormat::Sarif));
+        assert_eq!(detect_format(r#"{"SchemaVersion": 2, "ArtifactName": ".", "Results": []}"#), Some(ReportFormat::Trivy));
+        assert_eq!(detect_format(r#"{"results": [{"check_id": "rule"}], "errors": []}"#), Some(ReportFormat::Semgrep));
+        assert_eq!(detect_format(r#"[{"RuleID": "aws-access-token", "Match": "AKIA..."}]"#), Some(ReportFormat::Gitleaks));
+        assert_eq!(detect_format(r#"[{"RuleID": "aws-access-token"}]"#), None);
+        assert_eq!(detect_format("[]"), None);
+        assert_eq!(detect_format(r#"{"issues": []}"#), None);
+    }
+
+    #[test]
+    fn should_normalize_paths() {
+
+        assert_eq!(normalize_path("file:///workspace/src/app.py"), "src/app.py");
+        assert_eq!(normalize_path("/workspace/src/app.py"), "src/app.py");
+        assert_eq!(normalize_path("./src/app.py"), "src/app.py");
+        assert_eq!(normalize_path("src/app.py"), "src/app.py");
+    }
+
+}
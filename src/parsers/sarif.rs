@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+use crate::models::CodeIssue;
+use super::normalize_path;
+
+// Fingerprint keys known to be stable across runs, CodeQL writes primaryLocationLineHash
+// in partialFingerprints and Semgrep writes matchBasedId in fingerprints
+const PREFERRED_FINGERPRINTS: [&str; 3] = ["primaryLocationLineHash/v1", "primaryLocationLineHash", "matchBasedId/v1"];
+
+#[derive(Deserialize)]
+struct SarifLog {
+
+    #[serde(default)]
+    runs: Vec<SarifRun>
+
+}
+
+#[derive(Deserialize)]
+struct SarifRun {
+
+    tool: Option<SarifTool>,
+
+    #[serde(default)]
+    results: Vec<SarifResult>
+
+}
+
+#[derive(Deserialize)]
+struct SarifTool {
+
+    driver: SarifDriver
+
+}
+
+#[derive(Deserialize)]
+struct SarifDriver {
+
+    #[serde(default)]
+    rules: Vec<SarifRule>
+
+}
+
+#[derive(Deserialize)]
+struct SarifRule {
+
+    id: String,
+
+    help: Option<SarifMessage>,
+
+    properties: Option<SarifProperties>
+
+}
+
+#[derive(Deserialize)]
+struct SarifProperties {
+
+    #[serde(default)]
+    tags: Vec<String>
+
+}
+
+#[derive(Deserialize)]
+struct SarifResult {
+
+    #[serde(rename = "ruleId")]
+    rule_id: Option<String>,
+
+    level: Option<String>,
+
+    message: Option<SarifMessage>,
+
+    #[serde(default)]
+    locations: Vec<SarifLocation>,
+
+    #[serde(default)]
+    fingerprints: BTreeMap<String, String>,
+
+    #[serde(default, rename = "partialFingerprints")]
+    partial_fingerprints: BTreeMap<String, String>
+
+}
+
+#[derive(Deserialize)]
+struct SarifMessage {
+
+    text: Option<String>
+
+}
+
+#[derive(Deserialize)]
+struct SarifLocation {
+
+    #[serde(rename = "physicalLocation")]
+    physical_location: Option<SarifPhysicalLocation>
+
+}
+
+#[derive(Deserialize)]
+struct SarifPhysicalLocation {
+
+    #[serde(rename = "artifactLocation")]
+    artifact_location: Option<SarifArtifactLocation>,
+
+    region: Option<SarifRegion>
+
+}
+
+#[derive(Deserialize)]
+struct SarifArtifactLocation {
+
+    uri: Option<String>
+
+}
+
+#[derive(Deserialize)]
+struct SarifRegion {
+
+    #[serde(rename = "startLine")]
+    start_line: Option<u32>,
+
+    #[serde(rename = "endLine")]
+    end_line: Option<u32>,
+
+    #[serde(rename = "startColumn")]
+    start_column: Option<u32>,
+
+    #[serde(rename = "endColumn")]
+    end_column: Option<u32>,
+
+    snippet: Option<SarifMessage>
+
+}
+
+/// Parse a SARIF 2.1 log, each result of each run becomes an issue
+pub fn parse(content: &str) -> Result<Vec<CodeIssue>, Error> {
+
+    let sarif_log: SarifLog = serde_json::from_str(content)?;
+    let mut issues: Vec<CodeIssue> = vec![];
+
+    for run in sarif_log.runs {
+
+        let rules: Vec<SarifRule> = run.tool.map(|tool| tool.driver.rules).unwrap_or_default();
+
+        for result in run.results {
+
+            let rule = rules.iter().find(|rule| Some(&rule.id) == result.rule_id.as_ref());
+            let message = result.message.and_then(|message| message.text);
+            let name = result.rule_id.clone().or(message.clone()).unwrap_or_else(|| "Unnamed SARIF result".to_string());
+
+            let physical_location = result.locations.into_iter()
+                .find_map(|location| location.physical_location);
+            let (artifact_location, region) = match physical_location {
+                Some(physical_location) => (physical_location.artifact_location, physical_location.region),
+                None => (None, None)
+            };
+
+            let references = rule
+                .and_then(|rule| rule.properties.as_ref())
+                .map(|properties| properties.tags.iter().filter_map(|tag| map_cwe_tag(tag)).collect())
+                .unwrap_or_default();
+
+            let issue = CodeIssue {
+                name,
+                severity: Some(map_level(result.level.as_deref())),
+                path: artifact_location.and_then(|artifact| artifact.uri).map(|uri| normalize_path(&uri)),
+                start_line: region.as_ref().and_then(|region| region.start_line),
+                end_line: region.as_ref().and_then(|region| region.end_line),
+                start_column: region.as_ref().and_then(|region| region.start_column),
+                end_column: region.as_ref().and_then(|region| region.end_column),
+                rule_id: result.rule_id,
+                message,
+                references,
+                remediation: rule.and_then(|rule| rule.help.as_ref()).and_then(|help| help.text.clone()),
+                snippet: region.and_then(|region| region.snippet).and_then(|snippet| snippet.text),
+                fingerprint: select_fingerprint(result.fingerprints, result.partial_fingerprints),
+                ..Default::default()
+            };
+            issues.push(issue);
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Pick a stable fingerprint when a result carries several of them, preferred keys
+/// come first (partial fingerprints are only used for these keys) and the lowest
+/// key is used otherwise
+fn select_fingerprint(mut fingerprints: BTreeMap<String, String>, mut partial_fingerprints: BTreeMap<String, String>) -> Option<String> {
+
+    for preferred_key in PREFERRED_FINGERPRINTS {
+        if let Some(fingerprint) = fingerprints.remove(preferred_key).or_else(|| partial_fingerprints.remove(preferred_key)) {
+            return Some(fingerprint);
+        }
+    }
+
+    fingerprints.into_values().next()
+}
+
+/// SARIF levels are mapped on the runner severities ("warning" is the SARIF default)
+fn map_level(level: Option<&str>) -> String {
+
+    match level {
+        Some("error") => "high",
+        Some("note") => "low",
+        Some("none") => "info",
+        _ => "medium"
+    }.to_string()
+}
+
+/// CodeQL style CWE tags ("external/cwe/cwe-079") are converted to "CWE-79"
+fn map_cwe_tag(tag: &str) -> Option<String> {
+
+    let raw_identifier = tag.to_lowercase().strip_prefix("external/cwe/cwe-")?.to_string();
+    let identifier: u32 = raw_identifier.parse().ok()?;
+
+    Some(format!("CWE-{}", identifier))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use std::collections::BTreeMap;
+
+    use super::{parse, select_fingerprint};
+
+    #[test]
+    fn should_parse_sarif_results() -> Result<(), Error> {
+
+        let content = r#"{
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {"driver": {"name": "CodeQL", "rules": [
+                    {"id": "js/sql-injection", "help": {"text": "Use prepared statements"}, "properties": {"tags": ["security", "external/cwe/cwe-089"]}}
+                ]}},
+                "results": [
+                    {
+                        "ruleId": "js/sql-injection",
+                        "level": "error",
+                        "message": {"text": "Query built from user input"},
+                        "locations": [{"physicalLocation": {
+                            "artifactLocation": {"uri": "src/db.js"},
+                            "region": {"startLine": 12, "startColumn": 5, "endLine": 12, "endColumn": 48}
+                        }}]
+                    },
+                    {"message": {"text": "Unused variable"}}
+                ]
+            }]
+        }"#;
+
+        let issues = parse(content)?;
+
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].name, "js/sql-injection");
+        assert_eq!(issues[0].severity.as_deref(), Some("high"));
+        assert_eq!(issues[0].path.as_deref(), Some("src/db.js"));
+        assert_eq!(issues[0].start_line, Some(12));
+        assert_eq!(issues[0].references, vec!["CWE-89".to_string()]);
+        assert_eq!(issues[0].remediation.as_deref(), Some("Use prepared statements"));
+        assert_eq!(issues[1].name, "Unused variable");
+        assert_eq!(issues[1].severity.as_deref(), Some("medium"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_select_stable_fingerprint() {
+
+        let fingerprints = BTreeMap::from([
+            ("zeta/v1".to_string(), "z".to_string()),
+            ("primaryLocationLineHash/v1".to_string(), "p".to_string()),
+            ("alpha/v1".to_string(), "a".to_string())
+        ]);
+        assert_eq!(Some("p".to_string()), select_fingerprint(fingerprints, BTreeMap::new()));
+
+        let fingerprints = BTreeMap::from([
+            ("zeta/v1".to_string(), "z".to_string()),
+            ("alpha/v1".to_string(), "a".to_string())
+        ]);
+        assert_eq!(Some("a".to_string()), select_fingerprint(fingerprints, BTreeMap::new()));
+        assert_eq!(None, select_fingerprint(BTreeMap::new(), BTreeMap::new()));
+    }
+
+    #[test]
+    fn should_read_codeql_partial_fingerprints() -> Result<(), Error> {
+
+        let content = r#"{
+            "version": "2.1.0",
+            "runs": [{
+                "tool": {"driver": {"name": "CodeQL", "semanticVersion": "2.17.0"}},
+                "results": [{
+                    "ruleId": "py/clear-text-logging-sensitive-data",
+                    "ruleIndex": 3,
+                    "message": {"text": "This expression logs sensitive data (password) as clear text."},
+                    "locations": [{"physicalLocation": {
+                        "artifactLocation": {"uri": "app/auth.py", "uriBaseId": "%SRCROOT%", "index": 0},
+                        "region": {"startLine": 42, "startColumn": 15, "endColumn": 38}
+                    }}],
+                    "partialFingerprints": {
+                        "primaryLocationLineHash": "c2f0a6e3b0f9e5a1:1",
+                        "primaryLocationStartColumnFingerprint": "10"
+                    }
+                }]
+            }]
+        }"#;
+
+        let issues = parse(content)?;
+
+        assert_eq!(issues[0].fingerprint.as_deref(), Some("c2f0a6e3b0f9e5a1:1"));
+
+        Ok(())
+    }
+
+}
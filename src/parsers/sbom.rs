@@ -0,0 +1,278 @@
+use std::collections::HashSet;
+
+use anyhow::{bail, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::models::SbomComponent;
+
+// SPDX placeholders for unknown licenses
+const SPDX_UNKNOWN_LICENSES: [&str; 2] = ["NOASSERTION", "NONE"];
+
+/// Software bill of materials formats understood by the runner (JSON documents)
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SbomFormat {
+
+    CycloneDx,
+
+    Spdx
+
+}
+
+#[derive(Deserialize)]
+struct CycloneDxDocument {
+
+    #[serde(rename = "specVersion")]
+    spec_version: String,
+
+    #[serde(default)]
+    components: Vec<CycloneDxComponent>
+
+}
+
+#[derive(Deserialize)]
+struct CycloneDxComponent {
+
+    #[serde(rename = "type")]
+    component_type: Option<String>,
+
+    name: String,
+
+    version: Option<String>,
+
+    purl: Option<String>,
+
+    #[serde(default)]
+    licenses: Vec<CycloneDxLicenseChoice>,
+
+    #[serde(default)]
+    components: Vec<CycloneDxComponent>     // Nested components (such as a package in an image)
+
+}
+
+#[derive(Deserialize)]
+struct CycloneDxLicenseChoice {
+
+    license: Option<CycloneDxLicense>,
+
+    expression: Option<String>
+
+}
+
+#[derive(Deserialize)]
+struct CycloneDxLicense {
+
+    id: Option<String>,
+
+    name: Option<String>
+
+}
+
+#[derive(Deserialize)]
+struct SpdxDocument {
+
+    #[serde(rename = "spdxVersion")]
+    spdx_version: String,
+
+    #[serde(default)]
+    packages: Vec<SpdxPackage>
+
+}
+
+#[derive(Deserialize)]
+struct SpdxPackage {
+
+    name: String,
+
+    #[serde(rename = "versionInfo")]
+    version_info: Option<String>,
+
+    #[serde(rename = "primaryPackagePurpose")]
+    primary_package_purpose: Option<String>,
+
+    #[serde(rename = "licenseConcluded")]
+    license_concluded: Option<String>,
+
+    #[serde(rename = "licenseDeclared")]
+    license_declared: Option<String>,
+
+    #[serde(rename = "externalRefs", default)]
+    external_refs: Vec<SpdxExternalRef>
+
+}
+
+#[derive(Deserialize)]
+struct SpdxExternalRef {
+
+    #[serde(rename = "referenceType")]
+    reference_type: String,
+
+    #[serde(rename = "referenceLocator")]
+    reference_locator: String
+
+}
+
+/// Detect an SBOM format based on the JSON document structure
+pub fn detect_sbom(content: &str) -> Option<SbomFormat> {
+
+    let document: Value = serde_json::from_str(content).ok()?;
+
+    if document.get("bomFormat").and_then(Value::as_str) == Some("CycloneDX") {
+        return Some(SbomFormat::CycloneDx);
+    }
+    if document.get("spdxVersion").is_some() {
+        return Some(SbomFormat::Spdx);
+    }
+
+    None
+}
+
+/// Validate an SBOM document and normalize its components, duplicates are removed
+pub fn parse_sbom(format: SbomFormat, content: &str) -> Result<Vec<SbomComponent>, Error> {
+
+    let components = match format {
+        SbomFormat::CycloneDx => parse_cyclonedx(content)?,
+        SbomFormat::Spdx => parse_spdx(content)?
+    };
+
+    let mut known_components: HashSet<(String, Option<String>, Option<String>)> = HashSet::new();
+    let unique_components = components.into_iter()
+        .filter(|component| known_components.insert((component.name.to_string(), component.version.clone(), component.purl.clone())))
+        .collect();
+
+    Ok(unique_components)
+}
+
+fn parse_cyclonedx(content: &str) -> Result<Vec<SbomComponent>, Error> {
+
+    let document: CycloneDxDocument = serde_json::from_str(content)?;
+    if !document.spec_version.starts_with("1.") {
+        bail!("Unsupported CycloneDX specification version {}", document.spec_version);
+    }
+
+    let mut components: Vec<SbomComponent> = vec![];
+    flatten_cyclonedx(document.components, &mut components);
+
+    Ok(components)
+}
+
+fn flatten_cyclonedx(cyclonedx_components: Vec<CycloneDxComponent>, components: &mut Vec<SbomComponent>) {
+
+    for cyclonedx_component in cyclonedx_components {
+
+        let licenses = cyclonedx_component.licenses.into_iter()
+            .filter_map(|license_choice| {
+                license_choice.expression
+                    .or_else(|| license_choice.license.and_then(|license| license.id.or(license.name)))
+            })
+            .collect();
+
+        components.push(SbomComponent {
+            name: cyclonedx_component.name,
+            version: cyclonedx_component.version,
+            purl: cyclonedx_component.purl,
+            component_type: cyclonedx_component.component_type,
+            licenses
+        });
+
+        flatten_cyclonedx(cyclonedx_component.components, components);
+    }
+}
+
+fn parse_spdx(content: &str) -> Result<Vec<SbomComponent>, Error> {
+
+    let document: SpdxDocument = serde_json::from_str(content)?;
+    if !document.spdx_version.starts_with("SPDX-2.") {
+        bail!("Unsupported SPDX version {}", document.spdx_version);
+    }
+
+    let components = document.packages.into_iter()
+        .map(|package| {
+
+            let purl = package.external_refs.into_iter()
+                .find(|external_ref| external_ref.reference_type == "purl")
+                .map(|external_ref| external_ref.reference_locator);
+
+            // The concluded license is preferred, the declared one is used as a fallback
+            let license = [package.license_concluded, package.license_declared].into_iter()
+                .flatten()
+                .find(|license| !SPDX_UNKNOWN_LICENSES.contains(&license.as_str()));
+
+            SbomComponent {
+                name: package.name,
+                version: package.version_info,
+                purl,
+                component_type: package.primary_package_purpose.map(|purpose| purpose.to_lowercase()),
+                licenses: license.into_iter().collect()
+            }
+        })
+        .collect();
+
+    Ok(components)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use super::{detect_sbom, parse_sbom, SbomFormat};
+
+    #[test]
+    fn should_parse_cyclonedx_components() -> Result<(), Error> {
+
+        let content = r#"{
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "components": [
+                {
+                    "type": "library", "name": "serde", "version": "1.0.200", "purl": "pkg:cargo/serde@1.0.200",
+                    "licenses": [{"expression": "MIT OR Apache-2.0"}]
+                },
+                {
+                    "type": "library", "name": "log", "version": "0.4.21", "licenses": [{"license": {"id": "MIT"}}],
+                    "components": [{"type": "library", "name": "value-bag", "version": "1.9.0"}]
+                },
+                {"type": "library", "name": "serde", "version": "1.0.200", "purl": "pkg:cargo/serde@1.0.200"}
+            ]
+        }"#;
+
+        assert_eq!(detect_sbom(content), Some(SbomFormat::CycloneDx));
+
+        let components = parse_sbom(SbomFormat::CycloneDx, content)?;
+
+        assert_eq!(components.len(), 3);
+        assert_eq!(components[0].licenses, vec!["MIT OR Apache-2.0".to_string()]);
+        assert_eq!(components[2].name, "value-bag");
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_parse_spdx_packages() -> Result<(), Error> {
+
+        let content = r#"{
+            "spdxVersion": "SPDX-2.3",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "packages": [
+                {
+                    "name": "lodash", "versionInfo": "4.17.21", "licenseConcluded": "NOASSERTION", "licenseDeclared": "MIT",
+                    "externalRefs": [{"referenceCategory": "PACKAGE-MANAGER", "referenceType": "purl", "referenceLocator": "pkg:npm/lodash@4.17.21"}]
+                }
+            ]
+        }"#;
+
+        assert_eq!(detect_sbom(content), Some(SbomFormat::Spdx));
+
+        let components = parse_sbom(SbomFormat::Spdx, content)?;
+
+        assert_eq!(components.len(), 1);
+        assert_eq!(components[0].purl.as_deref(), Some("pkg:npm/lodash@4.17.21"));
+        assert_eq!(components[0].licenses, vec!["MIT".to_string()]);
+
+        assert!(parse_sbom(SbomFormat::Spdx, r#"{"spdxVersion": "SPDX-3.0", "packages": []}"#).is_err());
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,186 @@
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+use crate::models::CodeIssue;
+use super::normalize_path;
+
+#[derive(Deserialize)]
+struct SemgrepOutput {
+
+    #[serde(default)]
+    results: Vec<SemgrepResult>
+
+}
+
+#[derive(Deserialize)]
+struct SemgrepResult {
+
+    check_id: String,
+
+    path: Option<String>,
+
+    start: Option<SemgrepPosition>,
+
+    end: Option<SemgrepPosition>,
+
+    extra: Option<SemgrepExtra>
+
+}
+
+#[derive(Deserialize)]
+struct SemgrepPosition {
+
+    line: u32,
+
+    col: u32
+
+}
+
+#[derive(Deserialize)]
+struct SemgrepExtra {
+
+    severity: Option<String>,
+
+    message: Option<String>,
+
+    lines: Option<String>,
+
+    fix: Option<String>,
+
+    metadata: Option<SemgrepMetadata>
+
+}
+
+#[derive(Deserialize)]
+struct SemgrepMetadata {
+
+    cwe: Option<SemgrepCwe>
+
+}
+
+/// Rules list their CWE entries, some rules only give a single string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SemgrepCwe {
+
+    Single(String),
+
+    Multiple(Vec<String>)
+
+}
+
+impl SemgrepCwe {
+
+    fn into_vec(self) -> Vec<String> {
+
+        match self {
+            SemgrepCwe::Single(cwe) => vec![cwe],
+            SemgrepCwe::Multiple(cwes) => cwes
+        }
+    }
+
+}
+
+/// Parse a Semgrep JSON output (semgrep scan --json)
+pub fn parse(content: &str) -> Result<Vec<CodeIssue>, Error> {
+
+    let output: SemgrepOutput = serde_json::from_str(content)?;
+
+    let issues = output.results.into_iter()
+        .map(|result| {
+
+            let extra = result.extra.unwrap_or(SemgrepExtra { severity: None, message: None, lines: None, fix: None, metadata: None });
+
+            // CWE entries look like "CWE-95: Improper Neutralization of Directives..."
+            let references = extra.metadata
+                .and_then(|metadata| metadata.cwe)
+                .map(|cwe| cwe.into_vec().iter().map(|cwe| cwe.split(':').next().unwrap_or(cwe).trim().to_string()).collect())
+                .unwrap_or_default();
+
+            CodeIssue {
+                name: result.check_id.to_string(),
+                severity: Some(map_severity(extra.severity.as_deref())),
+                path: result.path.map(|path| normalize_path(&path)),
+                start_line: result.start.as_ref().map(|start| start.line),
+                end_line: result.end.as_ref().map(|end| end.line),
+                start_column: result.start.as_ref().map(|start| start.col),
+                end_column: result.end.as_ref().map(|end| end.col),
+                rule_id: Some(result.check_id),
+                message: extra.message,
+                references,
+                remediation: extra.fix.map(|fix| format!("Replace with: {}", fix)),
+                // Semgrep replaces the lines with "requires login" for some registry rules
+                snippet: extra.lines.filter(|lines| lines != "requires login"),
+                ..Default::default()
+            }
+        })
+        .collect();
+    Ok(issues)
+}
+
+fn map_severity(severity: Option<&str>) -> String {
+
+    match severity {
+        Some("ERROR") => "high",
+        Some("INFO") => "low",
+        _ => "medium"
+    }.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use super::parse;
+
+    #[test]
+    fn should_parse_semgrep_results() -> Result<(), Error> {
+
+        let content = r#"{
+            "results": [
+                {
+                    "check_id": "python.lang.security.eval",
+                    "path": "app.py",
+                    "start": {"line": 4, "col": 1, "offset": 30},
+                    "end": {"line": 4, "col": 15, "offset": 44},
+                    "extra": {"severity": "ERROR", "message": "Avoid eval", "lines": "eval(payload)", "metadata": {"cwe": ["CWE-95: Eval Injection"]}}
+                },
+                {"check_id": "python.lang.best-practice.open", "path": "io.py", "extra": {"severity": "INFO"}}
+            ],
+            "errors": []
+        }"#;
+
+        let issues = parse(content)?;
+
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].name, "python.lang.security.eval");
+        assert_eq!(issues[0].severity.as_deref(), Some("high"));
+        assert_eq!(issues[0].path.as_deref(), Some("app.py"));
+        assert_eq!(issues[0].end_column, Some(15));
+        assert_eq!(issues[0].message.as_deref(), Some("Avoid eval"));
+        assert_eq!(issues[0].references, vec!["CWE-95".to_string()]);
+        assert_eq!(issues[1].severity.as_deref(), Some("low"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn should_accept_single_cwe() -> Result<(), Error> {
+
+        let content = r#"{
+            "results": [{
+                "check_id": "javascript.express.security.xss",
+                "path": "server.js",
+                "extra": {"severity": "WARNING", "metadata": {"cwe": "CWE-79: Improper Neutralization of Input During Web Page Generation"}}
+            }],
+            "errors": []
+        }"#;
+
+        let issues = parse(content)?;
+
+        assert_eq!(issues[0].references, vec!["CWE-79".to_string()]);
+
+        Ok(())
+    }
+
+}
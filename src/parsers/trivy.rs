@@ -0,0 +1,240 @@
+use anyhow::{Error, Result};
+use serde::Deserialize;
+
+use crate::models::CodeIssue;
+use super::normalize_path;
+
+#[derive(Deserialize)]
+struct TrivyReport {
+
+    #[serde(rename = "Results", default)]
+    results: Vec<TrivyResult>
+
+}
+
+#[derive(Deserialize)]
+struct TrivyResult {
+
+    #[serde(rename = "Target")]
+    target: Option<String>,
+
+    #[serde(rename = "Vulnerabilities", default)]
+    vulnerabilities: Vec<TrivyVulnerability>,
+
+    #[serde(rename = "Misconfigurations", default)]
+    misconfigurations: Vec<TrivyMisconfiguration>,
+
+    #[serde(rename = "Secrets", default)]
+    secrets: Vec<TrivySecret>
+
+}
+
+#[derive(Deserialize)]
+struct TrivyVulnerability {
+
+    #[serde(rename = "VulnerabilityID")]
+    vulnerability_id: String,
+
+    #[serde(rename = "PkgName")]
+    package_name: String,
+
+    #[serde(rename = "InstalledVersion")]
+    installed_version: Option<String>,
+
+    #[serde(rename = "FixedVersion")]
+    fixed_version: Option<String>,
+
+    #[serde(rename = "Title")]
+    title: Option<String>,
+
+    #[serde(rename = "CweIDs", default)]
+    cwe_ids: Vec<String>,
+
+    #[serde(rename = "PrimaryURL")]
+    primary_url: Option<String>,
+
+    #[serde(rename = "Severity")]
+    severity: Option<String>
+
+}
+
+#[derive(Deserialize)]
+struct TrivyMisconfiguration {
+
+    #[serde(rename = "ID")]
+    id: String,
+
+    #[serde(rename = "Message")]
+    message: Option<String>,
+
+    #[serde(rename = "Resolution")]
+    resolution: Option<String>,
+
+    #[serde(rename = "PrimaryURL")]
+    primary_url: Option<String>,
+
+    #[serde(rename = "CauseMetadata")]
+    cause_metadata: Option<TrivyCauseMetadata>,
+
+    #[serde(rename = "Severity")]
+    severity: Option<String>
+
+}
+
+#[derive(Deserialize)]
+struct TrivyCauseMetadata {
+
+    #[serde(rename = "StartLine")]
+    start_line: Option<u32>,
+
+    #[serde(rename = "EndLine")]
+    end_line: Option<u32>
+
+}
+
+#[derive(Deserialize)]
+struct TrivySecret {
+
+    #[serde(rename = "RuleID")]
+    rule_id: String,
+
+    #[serde(rename = "Title")]
+    title: Option<String>,
+
+    #[serde(rename = "StartLine")]
+    start_line: Option<u32>,
+
+    #[serde(rename = "EndLine")]
+    end_line: Option<u32>,
+
+    #[serde(rename = "Match")]
+    secret_match: Option<String>,
+
+    #[serde(rename = "Severity")]
+    severity: Option<String>
+
+}
+
+/// Parse a Trivy JSON report with vulnerabilities, misconfigurations and secrets
+pub fn parse(content: &str) -> Result<Vec<CodeIssue>, Error> {
+
+    let report: TrivyReport = serde_json::from_str(content)?;
+    let mut issues: Vec<CodeIssue> = vec![];
+
+    for result in report.results {
+
+        let path = result.target.map(|target| normalize_path(&target));
+
+        for vulnerability in result.vulnerabilities {
+
+            let mut references = vulnerability.cwe_ids;
+            references.insert(0, vulnerability.vulnerability_id.to_string());
+            references.extend(vulnerability.primary_url);
+
+            let remediation = vulnerability.fixed_version
+                .filter(|fixed_version| !fixed_version.is_empty())
+                .map(|fixed_version| format!("Upgrade {} to {}", vulnerability.package_name, fixed_version));
+            let message = match (vulnerability.title, vulnerability.installed_version) {
+                (Some(title), _) => Some(title),
+                (None, Some(installed_version)) => Some(format!("{} {} is vulnerable", vulnerability.package_name, installed_version)),
+                (None, None) => None
+            };
+
+            issues.push(CodeIssue {
+                name: format!("{} in {}", vulnerability.vulnerability_id, vulnerability.package_name),
+                severity: map_severity(vulnerability.severity),
+                path: path.clone(),
+                rule_id: Some(vulnerability.vulnerability_id),
+                message,
+                references,
+                remediation,
+                ..Default::default()
+            });
+        }
+
+        for misconfiguration in result.misconfigurations {
+
+            let cause_metadata = misconfiguration.cause_metadata;
+            issues.push(CodeIssue {
+                name: misconfiguration.id.to_string(),
+                severity: map_severity(misconfiguration.severity),
+                path: path.clone(),
+                start_line: cause_metadata.as_ref().and_then(|cause| cause.start_line),
+                end_line: cause_metadata.as_ref().and_then(|cause| cause.end_line),
+                rule_id: Some(misconfiguration.id),
+                message: misconfiguration.message,
+                references: misconfiguration.primary_url.into_iter().collect(),
+                remediation: misconfiguration.resolution,
+                ..Default::default()
+            });
+        }
+
+        for secret in result.secrets {
+
+            issues.push(CodeIssue {
+                name: secret.rule_id.to_string(),
+                severity: map_severity(secret.severity),
+                path: path.clone(),
+                start_line: secret.start_line,
+                end_line: secret.end_line,
+                rule_id: Some(secret.rule_id),
+                message: secret.title,
+                snippet: secret.secret_match,
+                ..Default::default()
+            });
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Trivy severities are already aligned with the runner ones, except for "UNKNOWN"
+fn map_severity(severity: Option<String>) -> Option<String> {
+
+    severity
+        .map(|severity| severity.to_lowercase())
+        .filter(|severity| severity != "unknown")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::Error;
+    use super::parse;
+
+    #[test]
+    fn should_parse_trivy_results() -> Result<(), Error> {
+
+        let content = r#"{
+            "SchemaVersion": 2,
+            "ArtifactName": ".",
+            "Results": [
+                {
+                    "Target": "package-lock.json",
+                    "Vulnerabilities": [{
+                        "VulnerabilityID": "CVE-2021-23337", "PkgName": "lodash", "InstalledVersion": "4.17.15",
+                        "FixedVersion": "4.17.21", "CweIDs": ["CWE-94"], "Severity": "HIGH"
+                    }]
+                },
+                {
+                    "Target": "Dockerfile",
+                    "Misconfigurations": [{"ID": "DS002", "Severity": "UNKNOWN", "CauseMetadata": {"StartLine": 1, "EndLine": 3}}]
+                }
+            ]
+        }"#;
+
+        let issues = parse(content)?;
+
+        assert_eq!(issues.len(), 2);
+        assert_eq!(issues[0].name, "CVE-2021-23337 in lodash");
+        assert_eq!(issues[0].severity.as_deref(), Some("high"));
+        assert_eq!(issues[0].path.as_deref(), Some("package-lock.json"));
+        assert_eq!(issues[0].references, vec!["CVE-2021-23337".to_string(), "CWE-94".to_string()]);
+        assert_eq!(issues[0].remediation.as_deref(), Some("Upgrade lodash to 4.17.21"));
+        assert_eq!(issues[1].severity, None);
+        assert_eq!(issues[1].start_line, Some(1));
+
+        Ok(())
+    }
+
+}
@@ -0,0 +1,53 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::models::CodeIssue;
+
+static TEMP_DIR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary directory removed on drop, even when an assertion fails first
+pub struct TempDir {
+
+    path: PathBuf
+
+}
+
+impl TempDir {
+
+    pub fn new(prefix: &str) -> io::Result<TempDir> {
+
+        let count = TEMP_DIR_COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("chicon-{}-{}-{}", prefix, process::id(), count));
+        fs::create_dir_all(&path)?;
+
+        Ok(TempDir { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+}
+
+impl Drop for TempDir {
+
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+
+}
+
+/// Issue fixture identified by its rule and fingerprint, other fields are left empty
+pub fn build_issue(rule_id: &str, fingerprint: &str) -> CodeIssue {
+
+    CodeIssue {
+        name: rule_id.into(),
+        rule_id: Some(rule_id.into()),
+        fingerprint: Some(fingerprint.into()),
+        ..Default::default()
+    }
+}
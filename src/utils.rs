@@ -1,4 +1,7 @@
-use std::{time::SystemTime, path::Path, process};
+use std::{time::{SystemTime, UNIX_EPOCH}, path::Path, process};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
 
 use anyhow::Error;
 
@@ -16,6 +19,47 @@ pub fn compute_time_diff(start_time: SystemTime) -> Result<usize, Error> {
     Ok(computed_diff)
 }
 
+// Current UNIX timestamp in seconds
+pub fn current_timestamp() -> u64 {
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+// Current UTC date as (year, month, day)
+pub fn current_date() -> (i64, u32, u32) {
+    civil_from_days((current_timestamp() / 86_400) as i64)
+}
+
+/// Convert days since the UNIX epoch into a proleptic Gregorian date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+
+    let shifted_days = days + 719_468;
+    let era = shifted_days.div_euclid(146_097);
+    let day_of_era = shifted_days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+/// Open a file for reading without following a symbolic link on the last component,
+/// special files (such as FIFOs) are opened without blocking
+pub fn open_no_follow(path: &Path) -> io::Result<File> {
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+}
+
 /// Find a default config path
 pub fn find_default_config(requested_path: Option<&String>) -> Option<&str> {
 
@@ -45,3 +89,18 @@ pub fn find_default_config(requested_path: Option<&String>) -> Option<&str> {
     None
     
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::civil_from_days;
+
+    #[test]
+    fn should_convert_days_to_dates() {
+
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(20_744), (2026, 10, 18));
+    }
+
+}